@@ -0,0 +1,371 @@
+use crate::error::Error;
+use crate::shell::{self, Key, Shell};
+use chrono::{DateTime, FixedOffset, Local, Utc};
+use std::path::{Path, PathBuf};
+
+/// The environment variable that fixes the current time, so that output is
+/// reproducible. It holds the number of seconds since the Unix epoch.
+///
+/// See <https://reproducible-builds.org/specs/source-date-epoch/>.
+pub const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Determines the current time in the local time zone, unless
+/// `SOURCE_DATE_EPOCH` is set, in which case it gives that time in UTC.
+pub fn now() -> Result<DateTime<FixedOffset>, Error> {
+    match std::env::var(SOURCE_DATE_EPOCH) {
+        Ok(epoch) => match epoch.trim().parse::<i64>() {
+            Ok(secs) => match DateTime::<Utc>::from_timestamp(secs, 0) {
+                Some(t) => Ok(t.fixed_offset()),
+                None => Err(Error::SourceDateEpochInvalid(epoch)),
+            },
+            Err(_) => Err(Error::SourceDateEpochInvalid(epoch)),
+        },
+        Err(_) => Ok(Local::now().fixed_offset()),
+    }
+}
+
+/// Creates the shells that describe the time `now`, such as "koopa.date".
+pub fn time_shells(now: &DateTime<FixedOffset>) -> Vec<Shell> {
+    [
+        ("date", now.format("%Y-%m-%d").to_string()),
+        ("year", now.format("%Y").to_string()),
+        ("time", now.format("%H:%M:%S").to_string()),
+        (
+            "datetime",
+            now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, value)| Shell::with(format!("{}{}", shell::KEY_PREFIX, name), value))
+    .collect()
+}
+
+/// The names of the shells that describe the file being rendered.
+const PATH_SHELLS: [&str; 6] = ["name", "ext", "dir", "relpath", "source", "destdir"];
+
+/// Lists the keys of the shells created by [path_shells].
+pub fn path_keys() -> Vec<Key> {
+    PATH_SHELLS
+        .iter()
+        .map(|name| Key::with(format!("{}{}", shell::KEY_PREFIX, name)))
+        .collect()
+}
+
+/// Creates the shells that describe a single file being rendered from `src`
+/// to `dest`, where `root` is the destination of the whole operation:
+///
+/// - "koopa.name": the destination's name up to its first '.'
+/// - "koopa.ext": the destination's extension
+/// - "koopa.dir": the name of the destination's directory
+/// - "koopa.relpath": the destination's path from `root` ending in its name,
+///   separated by '/'
+/// - "koopa.source": the source's file name
+/// - "koopa.destdir": the absolute path of the destination's directory
+pub fn path_shells(src: &Path, dest: &Path, root: &Path) -> Result<Vec<Shell>, Error> {
+    let name = match dest.file_name().and_then(|s| s.to_str()) {
+        Some(s) => s.split('.').next().unwrap().to_string(),
+        None => return Err(Error::DestinationMissingFileName(dest.to_path_buf())),
+    };
+    let text = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().to_string());
+    let dest_dir = match std::path::absolute(dest) {
+        Ok(p) => p.parent().map(|p| p.to_path_buf()).unwrap_or(p),
+        Err(_) => dest.parent().unwrap_or(Path::new("")).to_path_buf(),
+    };
+    // end with the same name as "koopa.name", so every extension is removed
+    let relpath = dest
+        .strip_prefix(root)
+        .unwrap_or(dest)
+        .parent()
+        .into_iter()
+        .flat_map(|p| p.components())
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .chain(std::iter::once(name.clone()))
+        .collect::<Vec<_>>()
+        .join("/");
+    Ok(path_keys()
+        .into_iter()
+        .zip([
+            name,
+            text(dest.extension()).unwrap_or_default(),
+            text(dest_dir.file_name()).unwrap_or_default(),
+            relpath,
+            text(src.file_name()).unwrap_or_default(),
+            dest_dir.to_string_lossy().to_string(),
+        ])
+        .map(|(key, value)| Shell::with(key.as_str().to_string(), value))
+        .collect())
+}
+
+/// Where the value of a built-in identity shell was found.
+pub const FROM_GIT: &str = "git config";
+pub const FROM_OS: &str = "os";
+
+/// Creates the shells that identify the user, "koopa.user" and
+/// "koopa.email", along with where each value was found.
+///
+/// Values are read from the git configuration of the repository containing
+/// `dir` and then the user's global git configuration. The user name falls
+/// back to the name of the account on the operating system.
+pub fn identity_shells(dir: &Path) -> Vec<(Shell, &'static str)> {
+    let files = git_config_files(dir);
+    let find = |key: &str| {
+        files.iter().find_map(|f| {
+            let text = std::fs::read_to_string(f).ok()?;
+            read_git_config(&text, "user", key)
+        })
+    };
+    let user = match find("name") {
+        Some(name) => Some((name, FROM_GIT)),
+        None => ["USER", "USERNAME"]
+            .iter()
+            .find_map(|v| std::env::var(v).ok())
+            .filter(|name| name.is_empty() == false)
+            .map(|name| (name, FROM_OS)),
+    };
+    let email = find("email").map(|email| (email, FROM_GIT));
+    [("user", user), ("email", email)]
+        .into_iter()
+        .filter_map(|(name, found)| {
+            let (value, origin) = found?;
+            Some((
+                Shell::with(format!("{}{}", shell::KEY_PREFIX, name), value),
+                origin,
+            ))
+        })
+        .collect()
+}
+
+/// Lists the git configuration files that apply to `dir`, from the highest
+/// priority to the lowest.
+fn git_config_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    // the repository's own configuration
+    if let Some(git_dir) = dir.ancestors().find_map(|d| git_dir(&d.join(".git"))) {
+        files.push(git_dir.join("config"));
+    }
+    // the user's global configuration
+    if let Some(home) = home::home_dir() {
+        files.push(home.join(".gitconfig"));
+        match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(xdg) if xdg.is_empty() == false => {
+                files.push(PathBuf::from(xdg).join("git").join("config"))
+            }
+            _ => files.push(home.join(".config").join("git").join("config")),
+        }
+    }
+    files
+}
+
+/// Locates the directory that holds a repository's configuration from its
+/// ".git" entry, which is a file that points elsewhere for worktrees and
+/// submodules.
+fn git_dir(dot_git: &Path) -> Option<PathBuf> {
+    if dot_git.is_dir() == true {
+        return Some(dot_git.to_path_buf());
+    }
+    let text = std::fs::read_to_string(dot_git).ok()?;
+    let target = text.trim().strip_prefix("gitdir:")?.trim();
+    let git_dir = dot_git.parent()?.join(target);
+    // worktrees share the configuration of the main repository
+    match std::fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => Some(git_dir.join(common.trim())),
+        Err(_) => Some(git_dir),
+    }
+}
+
+/// Finds the value of `key` within `section` of a git configuration file,
+/// where the last value written is the one that applies.
+fn read_git_config(text: &str, section: &str, key: &str) -> Option<String> {
+    let mut current = String::new();
+    let mut found = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with('#') == true || line.starts_with(';') == true {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            // subsections such as [remote "origin"] never match a plain section
+            current = match header.split_once(']') {
+                Some((name, _)) => name.trim().to_lowercase(),
+                None => String::new(),
+            };
+            continue;
+        }
+        if current != section {
+            continue;
+        }
+        if let Some((name, value)) = line.split_once('=') {
+            if name.trim().eq_ignore_ascii_case(key) == true {
+                found = Some(git_config_value(value));
+            }
+        }
+    }
+    found
+}
+
+/// Reads a value from a git configuration file, removing quotes, escapes,
+/// and any trailing comment.
+fn git_config_value(raw: &str) -> String {
+    let mut value = String::new();
+    let mut quoted = false;
+    // whitespace outside of quotes at the end is not part of the value
+    let mut end = 0;
+    let mut chars = raw.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => (),
+            },
+            '#' | ';' if quoted == false => break,
+            c => value.push(c),
+        }
+        if quoted == true || c.is_whitespace() == false {
+            end = value.len();
+        }
+    }
+    value.truncate(end);
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ut_time_shells() {
+        let now = DateTime::<Utc>::from_timestamp(1_700_000_000, 0)
+            .unwrap()
+            .fixed_offset();
+        assert_eq!(
+            time_shells(&now),
+            vec![
+                Shell::with(String::from("koopa.date"), String::from("2023-11-14")),
+                Shell::with(String::from("koopa.year"), String::from("2023")),
+                Shell::with(String::from("koopa.time"), String::from("22:13:20")),
+                Shell::with(
+                    String::from("koopa.datetime"),
+                    String::from("2023-11-14T22:13:20Z")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn ut_now_source_date_epoch() {
+        std::env::set_var(SOURCE_DATE_EPOCH, "1700000000");
+        assert_eq!(now().unwrap().to_rfc3339(), "2023-11-14T22:13:20+00:00");
+        std::env::set_var(SOURCE_DATE_EPOCH, "yesterday");
+        assert_eq!(
+            now(),
+            Err(Error::SourceDateEpochInvalid(String::from("yesterday")))
+        );
+        std::env::remove_var(SOURCE_DATE_EPOCH);
+    }
+
+    #[test]
+    fn ut_read_git_config() {
+        let text = r#"
+# a comment
+[core]
+    name = not-a-user
+[user]
+    name = Ada Lovelace ; the first programmer
+    email = "ada@example.com" # work
+    signingkey = "  spaced  "
+[remote "origin"]
+    name = origin
+[User]
+    Name = "Ada \"Countess\" Lovelace"
+"#;
+        assert_eq!(
+            read_git_config(text, "user", "name"),
+            Some(String::from("Ada \"Countess\" Lovelace"))
+        );
+        assert_eq!(
+            read_git_config(text, "user", "email"),
+            Some(String::from("ada@example.com"))
+        );
+        assert_eq!(
+            read_git_config(text, "user", "signingkey"),
+            Some(String::from("  spaced  "))
+        );
+        assert_eq!(read_git_config(text, "user", "gpgsign"), None);
+    }
+
+    #[test]
+    fn ut_identity_from_repository() {
+        let dir = std::env::temp_dir().join("koopa-ut-identity");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(
+            dir.join(".git").join("config"),
+            "[user]\n\tname = Ada\n\temail = ada@example.com\n",
+        )
+        .unwrap();
+        assert_eq!(
+            identity_shells(&dir.join("src")),
+            vec![
+                (
+                    Shell::with(String::from("koopa.user"), String::from("Ada")),
+                    FROM_GIT
+                ),
+                (
+                    Shell::with(String::from("koopa.email"), String::from("ada@example.com")),
+                    FROM_GIT
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn ut_path_shells() {
+        let root = std::env::temp_dir().join("out");
+        let shells = path_shells(
+            Path::new("templates/lib/fifo.h.in"),
+            &root.join("include").join("fifo.h"),
+            &root,
+        )
+        .unwrap();
+        let get = |name: &str| {
+            shells
+                .iter()
+                .find(|s| s.key().get_name() == name)
+                .map(|s| s.value().to_string())
+                .unwrap()
+        };
+        assert_eq!(get("name"), "fifo");
+        assert_eq!(get("ext"), "h");
+        assert_eq!(get("dir"), "include");
+        assert_eq!(get("relpath"), "include/fifo");
+        assert_eq!(get("source"), "fifo.h.in");
+        assert_eq!(
+            get("destdir"),
+            root.join("include").to_string_lossy().to_string()
+        );
+        // every extension is removed from both the name and the path
+        let shells = path_shells(
+            Path::new("adder.tb.vhd"),
+            &root.join("sim.v2").join("adder.tb.vhd"),
+            &root,
+        )
+        .unwrap();
+        let get = |name: &str| {
+            shells
+                .iter()
+                .find(|s| s.key().get_name() == name)
+                .map(|s| s.value().to_string())
+                .unwrap()
+        };
+        assert_eq!(get("name"), "adder");
+        assert_eq!(get("relpath"), "sim.v2/adder");
+        assert_eq!(
+            path_shells(Path::new("a"), Path::new("/"), Path::new("/")),
+            Err(Error::DestinationMissingFileName(PathBuf::from("/")))
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use crate::error::Error;
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+
+/// Runs the commands that shells take their values from, such that each
+/// command runs at most once.
+#[derive(Debug, Default)]
+pub struct Commands {
+    outputs: HashMap<String, String>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the command with the system's shell and returns what it wrote to
+    /// stdout, without the final line ending. A command that already ran gives
+    /// the same output again without running.
+    pub fn run(&mut self, cmd: &str) -> Result<String, Error> {
+        if let Some(output) = self.outputs.get(cmd) {
+            return Ok(output.clone());
+        }
+        let failed = |e: String| Error::CommandFailed(cmd.to_string(), e);
+        let output = match Self::shell(cmd).stdin(Stdio::null()).output() {
+            Ok(o) => o,
+            Err(e) => return Err(failed(Error::lowerize(e.to_string()))),
+        };
+        if output.status.success() == false {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(failed(match stderr.is_empty() {
+                true => output.status.to_string(),
+                false => stderr,
+            }));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout)
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        self.outputs.insert(cmd.to_string(), stdout.clone());
+        Ok(stdout)
+    }
+
+    #[cfg(windows)]
+    fn shell(cmd: &str) -> Command {
+        let mut command = Command::new("cmd");
+        command.args(["/C", cmd]);
+        command
+    }
+
+    #[cfg(not(windows))]
+    fn shell(cmd: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.args(["-c", cmd]);
+        command
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(windows))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ut_run_commands() {
+        let mut commands = Commands::new();
+        assert_eq!(commands.run("printf 'a b\\n\\n'"), Ok(String::from("a b")));
+        // each command runs once, so its output does not change
+        let cmd = "date +%N";
+        assert_eq!(commands.run(cmd), commands.run(cmd));
+        assert_eq!(
+            commands.run("echo oops >&2; exit 3"),
+            Err(Error::CommandFailed(
+                String::from("echo oops >&2; exit 3"),
+                String::from("oops")
+            ))
+        );
+    }
+}
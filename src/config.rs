@@ -1,5 +1,7 @@
 use crate::{
+    encoding::{Encoding, Newline},
     shell::{Key, Shell, Value},
+    template::{Delimiters, Indent, Partials},
     Error,
 };
 use serde::Deserialize;
@@ -12,8 +14,10 @@ use std::{
 pub const CONFIG_DIR: &str = ".koopa";
 pub const IGNORE_FILE: &str = ".koopaignore";
 pub const CONFIG_FILE: &str = "shells.toml";
+pub const SETTINGS_FILE: &str = "koopa.toml";
+pub const SCRIPT_FILE: &str = "shells.rhai";
 
-use ignore::gitignore::Gitignore;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 
 #[derive(Debug)]
 pub struct IgnoreFile {
@@ -25,7 +29,7 @@ impl IgnoreFile {
         Self { inner: None }
     }
 
-    pub fn load(p: &PathBuf) -> Result<Self, Error> {
+    pub fn load(p: &Path) -> Result<Self, Error> {
         let ignore_file = p.join(IGNORE_FILE);
         if ignore_file.exists() == true && ignore_file.is_file() == true {
             let _ = match std::fs::read_to_string(&ignore_file) {
@@ -55,16 +59,84 @@ impl IgnoreFile {
             None => false,
         }
     }
+}
+
+/// The key reserved for naming the command that produces a shell's value, as
+/// in `user = { cmd = "git config user.name" }`.
+const COMMAND_KEY: &str = "cmd";
+
+/// A shell as written in a shells file, which either holds its value, names
+/// a command that produces it, or is a table that groups more shells under its
+/// name.
+#[derive(Debug, PartialEq, Clone)]
+enum ShellEntry {
+    Value(Value),
+    Command(String),
+    Table(HashMap<Key, ShellEntry>),
+}
 
-    pub fn exists(&self) -> bool {
-        self.inner.is_some()
+/// A shell as read from a shells file, before any commands are recognized.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum RawEntry {
+    Value(Value),
+    Table(HashMap<Key, RawEntry>),
+}
+
+impl ShellEntry {
+    /// Recognizes the tables that name a command, where `name` is the full
+    /// name of the entry. A table holding the reserved `cmd` key cannot hold
+    /// anything else, since it would be unclear if it names a command or a
+    /// shell called "cmd".
+    fn from_raw(name: &str, raw: RawEntry) -> Result<Self, String> {
+        let mut table = match raw {
+            RawEntry::Value(v) => return Ok(Self::Value(v)),
+            RawEntry::Table(t) => t,
+        };
+        match table.remove(&Key::with(COMMAND_KEY.to_string())) {
+            Some(RawEntry::Value(Value::Str(cmd))) if table.is_empty() == true => {
+                return Ok(Self::Command(cmd))
+            }
+            Some(_) if table.is_empty() == true => {
+                return Err(format!(
+                    "the \"{}\" of shell \"{}\" must be a string",
+                    COMMAND_KEY, name
+                ))
+            }
+            Some(_) => {
+                return Err(format!(
+                    "shell \"{}\" cannot hold \"{}\" alongside other shells; \"{}\" is reserved for naming a command",
+                    name, COMMAND_KEY, COMMAND_KEY
+                ))
+            }
+            None => (),
+        }
+        let mut entries = HashMap::new();
+        for (key, raw) in table {
+            let entry = Self::from_raw(&format!("{}.{}", name, key.as_str()), raw)?;
+            entries.insert(key, entry);
+        }
+        Ok(Self::Table(entries))
     }
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
-#[serde(transparent, deny_unknown_fields)]
+#[serde(try_from = "HashMap<Key, RawEntry>")]
 pub struct ConfigFile {
-    shells: HashMap<Key, Value>,
+    shells: HashMap<Key, ShellEntry>,
+}
+
+impl TryFrom<HashMap<Key, RawEntry>> for ConfigFile {
+    type Error = String;
+
+    fn try_from(value: HashMap<Key, RawEntry>) -> Result<Self, Self::Error> {
+        let mut shells = HashMap::new();
+        for (key, raw) in value {
+            let entry = ShellEntry::from_raw(key.as_str(), raw)?;
+            shells.insert(key, entry);
+        }
+        Ok(Self { shells: shells })
+    }
 }
 
 impl ConfigFile {
@@ -74,7 +146,29 @@ impl ConfigFile {
         }
     }
 
-    fn load(p: &PathBuf) -> Result<ConfigFile, Error> {
+    /// Lists every shell with a value or command, where the shells of a table
+    /// are named within the table's namespace.
+    fn entries(&self) -> Vec<(Key, ShellEntry)> {
+        let mut entries = Vec::new();
+        Self::flatten(&self.shells, "", &mut entries);
+        entries
+    }
+
+    fn flatten(
+        shells: &HashMap<Key, ShellEntry>,
+        namespace: &str,
+        entries: &mut Vec<(Key, ShellEntry)>,
+    ) {
+        for (key, entry) in shells {
+            let name = format!("{}{}", namespace, key.as_str());
+            match entry {
+                ShellEntry::Table(table) => Self::flatten(table, &format!("{}.", name), entries),
+                _ => entries.push((Key::with(name), entry.clone())),
+            }
+        }
+    }
+
+    fn load(p: &Path) -> Result<ConfigFile, Error> {
         let shell_file = p.join(CONFIG_FILE);
         if shell_file.exists() == true && shell_file.is_file() == true {
             let data = match std::fs::read_to_string(&shell_file) {
@@ -91,11 +185,256 @@ impl ConfigFile {
     }
 }
 
+/// A pair of opening and closing delimiters for placeholders and blocks, where
+/// either pair may be left out to keep the existing delimiters.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DelimitersEntry {
+    variable: Option<[String; 2]>,
+    block: Option<[String; 2]>,
+}
+
+impl DelimitersEntry {
+    fn apply(&self, delimiters: &mut Delimiters) {
+        if let Some([open, close]) = &self.variable {
+            delimiters.set_variable(open, close);
+        }
+        if let Some([open, close]) = &self.block {
+            delimiters.set_block(open, close);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleEntry {
+    glob: String,
+    #[serde(default)]
+    delimiters: DelimitersEntry,
+    indent: Option<Indent>,
+    verbatim: Option<bool>,
+    encoding: Option<Encoding>,
+    newline: Option<Newline>,
+    renderer: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SettingsEntry {
+    #[serde(default)]
+    delimiters: DelimitersEntry,
+    indent: Option<Indent>,
+    verbatim: Option<bool>,
+    encoding: Option<Encoding>,
+    newline: Option<Newline>,
+    renderer: Option<String>,
+    #[serde(default)]
+    rules: Vec<RuleEntry>,
+}
+
+/// Settings that only apply to the files matching a glob.
+#[derive(Debug, Clone)]
+struct Rule {
+    glob: Gitignore,
+    delimiters: DelimitersEntry,
+    indent: Option<Indent>,
+    verbatim: Option<bool>,
+    encoding: Option<Encoding>,
+    newline: Option<Newline>,
+    renderer: Option<String>,
+}
+
+impl Rule {
+    /// Checks if the rule applies to the path, which includes every path
+    /// within a directory that the glob matches.
+    fn is_match(&self, path: &Path) -> bool {
+        // parents can only be checked for paths under the glob's root
+        match path.starts_with(self.glob.path()) {
+            true => self
+                .glob
+                .matched_path_or_any_parents(path, path.is_dir())
+                .is_ignore(),
+            false => self.glob.matched(path, path.is_dir()).is_ignore(),
+        }
+    }
+}
+
+/// The settings defined within a single configuration folder.
+#[derive(Debug, Clone, Default)]
+pub struct SettingsFile {
+    delimiters: DelimitersEntry,
+    indent: Option<Indent>,
+    verbatim: Option<bool>,
+    encoding: Option<Encoding>,
+    newline: Option<Newline>,
+    renderer: Option<String>,
+    rules: Vec<Rule>,
+}
+
+impl SettingsFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn load(p: &PathBuf) -> Result<Self, Error> {
+        let settings_file = p.join(SETTINGS_FILE);
+        if settings_file.exists() == true && settings_file.is_file() == true {
+            let data = match std::fs::read_to_string(&settings_file) {
+                Ok(r) => r,
+                Err(e) => {
+                    return Err(Error::FileRead(
+                        settings_file,
+                        Error::lowerize(e.to_string()),
+                    ))
+                }
+            };
+            let entry: SettingsEntry = match toml::de::from_str(&data) {
+                Ok(r) => r,
+                Err(e) => {
+                    return Err(Error::TomlParse(
+                        settings_file,
+                        Error::lowerize(e.to_string()),
+                    ))
+                }
+            };
+            // globs are matched relative to the configuration folder
+            let mut rules = Vec::new();
+            for rule in entry.rules {
+                let mut builder = GitignoreBuilder::new(p);
+                let glob = match builder.add_line(None, &rule.glob).and_then(|b| b.build()) {
+                    Ok(g) => g,
+                    Err(e) => {
+                        return Err(Error::SettingsInvalid(
+                            settings_file,
+                            Error::lowerize(e.to_string()),
+                        ))
+                    }
+                };
+                rules.push(Rule {
+                    glob: glob,
+                    delimiters: rule.delimiters,
+                    indent: rule.indent,
+                    verbatim: rule.verbatim,
+                    encoding: rule.encoding,
+                    newline: rule.newline,
+                    renderer: rule.renderer,
+                });
+            }
+            Ok(Self {
+                delimiters: entry.delimiters,
+                indent: entry.indent,
+                verbatim: entry.verbatim,
+                encoding: entry.encoding,
+                newline: entry.newline,
+                renderer: entry.renderer,
+                rules: rules,
+            })
+        } else {
+            Ok(Self::new())
+        }
+    }
+}
+
+/// The settings gathered across every configuration folder, in the order the
+/// folders were loaded.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    roots: Vec<PathBuf>,
+    files: Vec<SettingsFile>,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the configuration's settings, which take priority over the
+    /// settings already added.
+    pub fn add(&mut self, config: &Config) {
+        self.roots.push(config.root.clone());
+        self.files.push(config.settings.clone());
+    }
+
+    /// Determines the delimiters to use for the file at `path`, starting from
+    /// `base`. Rules matching the file take priority over the folder-wide
+    /// delimiters.
+    pub fn delimiters_for(&self, path: &Path, base: &Delimiters) -> Result<Delimiters, Error> {
+        let mut delimiters = base.clone();
+        self.files
+            .iter()
+            .for_each(|f| f.delimiters.apply(&mut delimiters));
+        self.files
+            .iter()
+            .flat_map(|f| f.rules.iter())
+            .filter(|r| r.is_match(path) == true)
+            .for_each(|r| r.delimiters.apply(&mut delimiters));
+        match delimiters.validate() {
+            Ok(()) => Ok(delimiters),
+            Err(e) => Err(Error::DelimitersInvalid(path.to_path_buf(), e)),
+        }
+    }
+
+    /// Determines how multi-line values are indented for the file at `path`,
+    /// using `base` if it is not configured.
+    pub fn indent_for(&self, path: &Path, base: Indent) -> Indent {
+        self.find(path, |f| f.indent, |r| r.indent).unwrap_or(base)
+    }
+
+    /// Checks if the file at `path` is configured to be copied as-is without
+    /// being translated.
+    pub fn is_verbatim(&self, path: &Path) -> bool {
+        self.find(path, |f| f.verbatim, |r| r.verbatim)
+            .unwrap_or(false)
+    }
+
+    /// Determines the encoding to read and write the file at `path` with, if
+    /// one is configured rather than detected.
+    pub fn encoding_for(&self, path: &Path) -> Option<Encoding> {
+        self.find(path, |f| f.encoding, |r| r.encoding)
+    }
+
+    /// Determines the line endings to write the file at `path` with, if they
+    /// are configured rather than kept from the source.
+    pub fn newline_for(&self, path: &Path) -> Option<Newline> {
+        self.find(path, |f| f.newline, |r| r.newline)
+    }
+
+    /// Determines the name of the renderer to translate the file at `path`
+    /// with, if one is configured rather than koopa's own templates.
+    pub fn renderer_for(&self, path: &Path) -> Option<String> {
+        self.find(path, |f| f.renderer.clone(), |r| r.renderer.clone())
+    }
+
+    /// Finds the setting for the file at `path`, where the last matching rule
+    /// takes priority over the last folder-wide setting.
+    fn find<T>(
+        &self,
+        path: &Path,
+        global: fn(&SettingsFile) -> Option<T>,
+        rule: fn(&Rule) -> Option<T>,
+    ) -> Option<T> {
+        self.files
+            .iter()
+            .flat_map(|f| f.rules.iter())
+            .filter(|r| r.is_match(path) == true)
+            .rev()
+            .find_map(rule)
+            .or_else(|| self.files.iter().rev().find_map(global))
+    }
+
+    /// Lists the folders to search for partials, where the most recently added
+    /// folder is searched first.
+    pub fn partials(&self) -> Partials {
+        Partials::with(self.roots.iter().rev().cloned().collect())
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
     root: PathBuf,
     data: ConfigFile,
     ignore: IgnoreFile,
+    settings: SettingsFile,
 }
 
 impl Config {
@@ -104,6 +443,7 @@ impl Config {
         Ok(Self {
             data: ConfigFile::load(&root)?,
             ignore: IgnoreFile::load(&root)?,
+            settings: SettingsFile::load(&root)?,
             root: root,
         })
     }
@@ -123,15 +463,47 @@ impl Config {
         }
     }
 
+    pub fn get_root(&self) -> &PathBuf {
+        &self.root
+    }
+
     pub fn get_shells(&self) -> Vec<Shell> {
         self.data
-            .shells
-            .clone()
+            .entries()
             .into_iter()
-            .map(|(k, v)| Shell::from((k.into_koopa_key(), v)))
+            .filter_map(|(k, v)| match v {
+                ShellEntry::Value(v) => Some(Shell::from((k.into_koopa_key(), v))),
+                _ => None,
+            })
             .collect()
     }
 
+    /// Lists the shells that take their value from running a command, along
+    /// with the command for each.
+    pub fn get_commands(&self) -> Vec<(Key, String)> {
+        let mut commands: Vec<(Key, String)> = self
+            .data
+            .entries()
+            .into_iter()
+            .filter_map(|(k, v)| match v {
+                ShellEntry::Command(cmd) => Some((k.into_koopa_key(), cmd)),
+                _ => None,
+            })
+            .collect();
+        commands.sort();
+        commands
+    }
+
+    /// Locates the script that derives shells for this configuration, if it
+    /// has one.
+    pub fn get_script(&self) -> Option<PathBuf> {
+        let script = self.root.join(SCRIPT_FILE);
+        match script.is_file() {
+            true => Some(script),
+            false => None,
+        }
+    }
+
     pub fn get_sources(&self) -> Vec<(PathBuf, PathBuf)> {
         let mut entries = Vec::new();
         let _ = Self::visit_dirs(&self.root, &mut entries, true, &self.ignore);
@@ -154,20 +526,21 @@ impl Config {
                 let entry = entry?;
                 let path = entry.path();
                 // ignore hidden files if true
-                if ignore.is_ignored(&path) == false {
-                    if skip_hidden == false
-                        || entry.file_name().to_string_lossy().starts_with('.') == false
+                if ignore.is_ignored(&path) == false
+                    && (skip_hidden == false
+                        || entry.file_name().to_string_lossy().starts_with('.') == false)
+                {
+                    if path.is_dir() {
+                        // allow this directory to be a source
+                        cb.push(entry.path());
+                        Self::visit_dirs(&path, cb, skip_hidden, ignore)?;
+                    } else if skip_hidden == false
+                        || (entry.file_name() != CONFIG_FILE
+                            && entry.file_name() != SETTINGS_FILE
+                            && entry.file_name() != SCRIPT_FILE)
                     {
-                        if path.is_dir() {
-                            // allow this directory to be a source
-                            cb.push(entry.path());
-                            Self::visit_dirs(&path, cb, skip_hidden, ignore)?;
-                        } else {
-                            if skip_hidden == false || entry.file_name() != CONFIG_FILE {
-                                // allow this file to be a source
-                                cb.push(entry.path());
-                            }
-                        }
+                        // allow this file to be a source
+                        cb.push(entry.path());
                     }
                 }
             }
@@ -175,3 +548,165 @@ impl Config {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ut_config_shells() {
+        let dir = std::env::temp_dir().join("koopa-ut-commands");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(CONFIG_DIR)).unwrap();
+        std::fs::write(
+            dir.join(CONFIG_DIR).join(CONFIG_FILE),
+            r#"
+license = "MIT"
+
+[git]
+user = { cmd = "git config user.name" }
+remote.url = "https://example.com/repo.git"
+"#,
+        )
+        .unwrap();
+        let config = Config::new(dir.clone()).unwrap();
+        let mut shells = config.get_shells();
+        shells.sort_by(|a, b| a.key().cmp(b.key()));
+        assert_eq!(
+            shells,
+            vec![
+                Shell::with(
+                    String::from("koopa.git.remote.url"),
+                    String::from("https://example.com/repo.git")
+                ),
+                Shell::with(String::from("koopa.license"), String::from("MIT")),
+            ]
+        );
+        assert_eq!(
+            config.get_commands(),
+            vec![(
+                Key::with(String::from("koopa.git.user")),
+                String::from("git config user.name")
+            )]
+        );
+
+        for text in [
+            "user = { cmd = 1 }\n",
+            // a shell called "cmd" is not told apart from a command
+            "[build]\ncmd = \"make\"\ndir = \"out\"\n",
+        ] {
+            std::fs::write(dir.join(CONFIG_DIR).join(CONFIG_FILE), text).unwrap();
+            assert!(matches!(
+                Config::new(dir.clone()),
+                Err(Error::TomlParse(_, e)) if e.contains("cmd") == true
+            ));
+        }
+    }
+
+    #[test]
+    fn ut_settings_delimiters() {
+        let dir = std::env::temp_dir().join("koopa-ut-settings");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(CONFIG_DIR)).unwrap();
+        std::fs::write(
+            dir.join(CONFIG_DIR).join(SETTINGS_FILE),
+            r#"
+delimiters.variable = ["<%", "%>"]
+
+[[rules]]
+glob = "*.vue"
+delimiters = { variable = ["[[", "]]"], block = ["[%", "%]"] }
+
+[[rules]]
+glob = "helm/**"
+delimiters.variable = ["@@", "@@"]
+
+[[rules]]
+glob = "*.{cpp,vhd}"
+indent = "prefix"
+
+[[rules]]
+glob = "assets/"
+verbatim = true
+
+[[rules]]
+glob = "*.{bat,vcxproj}"
+newline = "crlf"
+
+[[rules]]
+glob = "legacy/*.vhd"
+encoding = "latin-1"
+
+[[rules]]
+glob = "*.j2"
+renderer = "jinja"
+"#,
+        )
+        .unwrap();
+        let mut settings = Settings::new();
+        settings.add(&Config::new(dir.clone()).unwrap());
+        let root = dir.join(CONFIG_DIR);
+
+        let mut expected = Delimiters::new();
+        expected.set_variable("<%", "%>");
+        assert_eq!(
+            settings.delimiters_for(&root.join("a.txt"), &Delimiters::new()),
+            Ok(expected)
+        );
+
+        let mut expected = Delimiters::new();
+        expected.set_variable("[[", "]]");
+        expected.set_block("[%", "%]");
+        assert_eq!(
+            settings.delimiters_for(&root.join("web/app.vue"), &Delimiters::new()),
+            Ok(expected.clone())
+        );
+        assert_eq!(
+            settings.delimiters_for(Path::new("/other/app.vue"), &Delimiters::new()),
+            Ok(expected)
+        );
+
+        let mut expected = Delimiters::new();
+        expected.set_variable("@@", "@@");
+        assert_eq!(
+            settings.delimiters_for(&root.join("helm/templates/a.yaml"), &Delimiters::new()),
+            Ok(expected)
+        );
+
+        assert_eq!(
+            settings.indent_for(&root.join("main.cpp"), Indent::Spaces),
+            Indent::Prefix
+        );
+        assert_eq!(
+            settings.indent_for(&root.join("du.vhd"), Indent::Spaces),
+            Indent::Prefix
+        );
+        assert_eq!(
+            settings.indent_for(&root.join("a.txt"), Indent::Spaces),
+            Indent::Spaces
+        );
+
+        assert_eq!(
+            settings.renderer_for(&root.join("ci/build.yml.j2")),
+            Some(String::from("jinja"))
+        );
+        assert_eq!(settings.renderer_for(&root.join("a.txt")), None);
+
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        assert!(settings.is_verbatim(&root.join("assets/logo.svg")) == true);
+        assert!(settings.is_verbatim(&root.join("assets")) == true);
+        assert!(settings.is_verbatim(&root.join("a.txt")) == false);
+
+        assert_eq!(
+            settings.newline_for(&root.join("run.bat")),
+            Some(Newline::CrLf)
+        );
+        assert_eq!(settings.newline_for(&root.join("a.txt")), None);
+        assert_eq!(
+            settings.encoding_for(&root.join("legacy/du.vhd")),
+            Some(Encoding::Latin1)
+        );
+        assert_eq!(settings.encoding_for(&root.join("du.vhd")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use serde::Deserialize;
+
+/// The character encodings that sources can be read and written in.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+pub enum Encoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "utf-16le")]
+    Utf16Le,
+    #[serde(rename = "utf-16be")]
+    Utf16Be,
+    #[serde(rename = "latin-1")]
+    Latin1,
+}
+
+impl Encoding {
+    /// Identifies the encoding from the byte order mark at the start of the
+    /// bytes, if there is one.
+    pub fn from_bom(bytes: &[u8]) -> Option<Self> {
+        [Self::Utf8, Self::Utf16Le, Self::Utf16Be]
+            .into_iter()
+            .find(|e| bytes.starts_with(e.bom()) == true)
+    }
+
+    /// The byte order mark that begins text in this encoding.
+    pub fn bom(&self) -> &'static [u8] {
+        match self {
+            Self::Utf8 => &[0xef, 0xbb, 0xbf],
+            Self::Utf16Le => &[0xff, 0xfe],
+            Self::Utf16Be => &[0xfe, 0xff],
+            Self::Latin1 => &[],
+        }
+    }
+
+    /// Decodes as much of the bytes as possible, returning the text along with
+    /// the number of bytes used. Unless `last` is set, a character cut off at
+    /// the end of the bytes is left to be decoded with the bytes that follow.
+    pub fn decode(&self, bytes: &[u8], last: bool) -> Result<(String, usize), String> {
+        match self {
+            Self::Utf8 => {
+                let valid = match std::str::from_utf8(bytes) {
+                    Ok(s) => s.len(),
+                    Err(e) if e.error_len().is_none() && last == false => e.valid_up_to(),
+                    Err(e) => return Err(e.to_string()),
+                };
+                let text = std::str::from_utf8(&bytes[..valid]).unwrap().to_string();
+                Ok((text, valid))
+            }
+            Self::Utf16Le | Self::Utf16Be => {
+                let mut units: Vec<u16> = bytes
+                    .chunks_exact(2)
+                    .map(|b| match self {
+                        Self::Utf16Le => u16::from_le_bytes([b[0], b[1]]),
+                        _ => u16::from_be_bytes([b[0], b[1]]),
+                    })
+                    .collect();
+                // leave the first half of a surrogate pair for the next bytes
+                if last == false && units.last().is_some_and(|u| (0xd800..0xdc00).contains(u)) {
+                    units.pop();
+                }
+                let used = units.len() * 2;
+                if last == true && used < bytes.len() {
+                    return Err(String::from("incomplete utf-16 code unit at end of input"));
+                }
+                match String::from_utf16(&units) {
+                    Ok(text) => Ok((text, used)),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+            Self::Latin1 => Ok((bytes.iter().map(|b| *b as char).collect(), bytes.len())),
+        }
+    }
+
+    /// Encodes the text, failing if it contains a character the encoding
+    /// cannot represent.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Utf8 => Ok(text.as_bytes().to_vec()),
+            Self::Utf16Le => Ok(text.encode_utf16().flat_map(|u| u.to_le_bytes()).collect()),
+            Self::Utf16Be => Ok(text.encode_utf16().flat_map(|u| u.to_be_bytes()).collect()),
+            Self::Latin1 => text
+                .chars()
+                .map(|c| match u8::try_from(c) {
+                    Ok(b) => Ok(b),
+                    Err(_) => Err(format!("character '{}' cannot be written as latin-1", c)),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The character sequences that can end a line.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Newline {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+/// How a source's text is stored, so its output can be written the same way.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Format {
+    encoding: Encoding,
+    bom: bool,
+    newline: Newline,
+}
+
+impl Format {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Determines the format from the bytes at the start of a source. The
+    /// encoding is found from the byte order mark unless it is given, and text
+    /// without one is read as UTF-8 when valid and as Latin-1 otherwise. The
+    /// newline style is taken from the first line ending.
+    pub fn detect(bytes: &[u8], encoding: Option<Encoding>) -> Self {
+        let encoding = match encoding.or_else(|| Encoding::from_bom(bytes)) {
+            Some(e) => e,
+            None => match Encoding::Utf8.decode(bytes, false) {
+                Ok(_) => Encoding::Utf8,
+                Err(_) => Encoding::Latin1,
+            },
+        };
+        let bom = encoding.bom().is_empty() == false && bytes.starts_with(encoding.bom());
+        let body = match bom {
+            true => &bytes[encoding.bom().len()..],
+            false => bytes,
+        };
+        let newline = match encoding.decode(body, false) {
+            Ok((text, _)) => match text.find('\n') {
+                Some(i) if text[..i].ends_with('\r') => Newline::CrLf,
+                _ => Newline::Lf,
+            },
+            Err(_) => Newline::Lf,
+        };
+        Self {
+            encoding: encoding,
+            bom: bom,
+            newline: newline,
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn has_bom(&self) -> bool {
+        self.bom
+    }
+
+    pub fn newline(&self) -> Newline {
+        self.newline
+    }
+
+    pub fn set_newline(&mut self, newline: Newline) {
+        self.newline = newline;
+    }
+
+    /// Converts text that uses `'\n'` line endings into bytes stored in this
+    /// format. The byte order mark is not included.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        match self.newline {
+            Newline::Lf => self.encoding.encode(text),
+            Newline::CrLf => self.encoding.encode(&text.replace('\n', "\r\n")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ut_detect_format() {
+        let format = Format::detect(b"\xef\xbb\xbf@echo off\r\nset X=1\r\n", None);
+        assert_eq!(format.encoding(), Encoding::Utf8);
+        assert!(format.has_bom() == true);
+        assert_eq!(format.newline(), Newline::CrLf);
+
+        let bytes: Vec<u8> = [0xff, 0xfe]
+            .into_iter()
+            .chain("<Project>\r\n".encode_utf16().flat_map(|u| u.to_le_bytes()))
+            .collect();
+        let format = Format::detect(&bytes, None);
+        assert_eq!(format.encoding(), Encoding::Utf16Le);
+        assert_eq!(format.newline(), Newline::CrLf);
+
+        let format = Format::detect(b"caf\xe9\n", None);
+        assert_eq!(format.encoding(), Encoding::Latin1);
+        assert!(format.has_bom() == false);
+        assert_eq!(format.newline(), Newline::Lf);
+
+        let format = Format::detect(b"plain\n", Some(Encoding::Latin1));
+        assert_eq!(format.encoding(), Encoding::Latin1);
+    }
+
+    #[test]
+    fn ut_decode_partial() {
+        let bytes: Vec<u8> = "a😀".encode_utf16().flat_map(|u| u.to_be_bytes()).collect();
+        // the surrogate pair and the odd byte are left for later
+        assert_eq!(
+            Encoding::Utf16Be.decode(&bytes[..5], false),
+            Ok((String::from("a"), 2))
+        );
+        assert_eq!(
+            Encoding::Utf16Be.decode(&bytes, true),
+            Ok((String::from("a😀"), 6))
+        );
+        assert!(Encoding::Utf16Be.decode(&bytes[..5], true).is_err());
+        assert_eq!(
+            Encoding::Utf8.decode(&"é".as_bytes()[..1], false),
+            Ok((String::new(), 0))
+        );
+        assert!(Encoding::Utf8.decode(&"é".as_bytes()[..1], true).is_err());
+    }
+
+    #[test]
+    fn ut_encode() {
+        let mut format = Format::detect(b"caf\xe9\r\n", None);
+        assert_eq!(format.encode("café\nx").unwrap(), b"caf\xe9\r\nx");
+        assert!(format.encode("😀").is_err());
+        format.set_newline(Newline::Lf);
+        assert_eq!(format.encode("a\nb").unwrap(), b"a\nb");
+    }
+}
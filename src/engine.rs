@@ -0,0 +1,810 @@
+use crate::builtin;
+use crate::config::{Config, IgnoreFile, Settings};
+use crate::encoding::Format;
+use crate::error::Error;
+use crate::filter::Filters;
+use crate::help::Message;
+use crate::renderer::{self, Context, Renderer, Renderers};
+use crate::script::Script;
+use crate::shell::{Computed, Key, Shell, ShellMap};
+use crate::stream;
+use crate::template::{Delimiters, Diagnostic, Indent, Template};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Renders text, files, and directories with a fixed set of shells and
+/// options. This is the entry point for using koopa as a library.
+///
+/// ```
+/// use koopa::{Engine, Shell};
+/// use std::str::FromStr;
+///
+/// let engine = Engine::builder()
+///     .shell(Shell::from_str("name=adder").unwrap())
+///     .build()
+///     .unwrap();
+/// assert_eq!(engine.render_str("entity {{ koopa.name }}").unwrap(), "entity adder");
+/// ```
+pub struct Engine {
+    shells: ShellMap,
+    filters: Filters,
+    renderers: Renderers,
+    scripts: Vec<Script>,
+    settings: Settings,
+    delimiters: Delimiters,
+    indent: Indent,
+    force: bool,
+    notify: Box<dyn Fn(Message)>,
+}
+
+impl Engine {
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::new()
+    }
+
+    pub fn shells(&self) -> &ShellMap {
+        &self.shells
+    }
+
+    /// Renders the text, failing with every problem found within it.
+    pub fn render_str(&self, text: &str) -> Result<String, Vec<Diagnostic>> {
+        let mut template = match Template::parse_with(text, &self.delimiters) {
+            Ok(t) => t,
+            Err(e) => return Err(vec![Diagnostic::from(e).quote(text, 1)]),
+        };
+        template.set_indent(self.indent);
+        let shells = self.resolve(self.shells.clone())?;
+        let mut result = String::new();
+        template.render_into(
+            &shells,
+            &self.filters,
+            &self.settings.partials(),
+            self.force,
+            self.notify.as_ref(),
+            &mut result,
+        )?;
+        Ok(result)
+    }
+
+    /// Renders the file at `src` into the file at `dest`, returning the number
+    /// of bytes written. Binary files and files marked as verbatim are copied
+    /// as-is. Nothing is left at `dest` if the file has problems.
+    pub fn render_file(&self, src: &Path, dest: &Path) -> Result<usize, Vec<Diagnostic>> {
+        self.permit(dest)?;
+        let (shells, per_file) = self.resolve_shared()?;
+        let root = dest.parent().unwrap_or(Path::new(""));
+        let shells = self.resolve_file(&shells, &per_file, src, dest, root)?;
+        self.copy_file(src, dest, &shells)
+    }
+
+    /// Renders every file within the directory at `src` into the same place
+    /// within `dest`, returning the number of bytes written. Shells built from
+    /// the shells that describe each file's path, such as `koopa.name`, are
+    /// evaluated for each file, while all others are evaluated once. Every problem
+    /// across the files is returned together, and nothing is left at `dest`
+    /// if any file has problems.
+    pub fn render_dir(&self, src: &Path, dest: &Path) -> Result<usize, Vec<Diagnostic>> {
+        self.permit(dest)?;
+        let (shells, per_file) = self.resolve_shared()?;
+
+        // get all the sources
+        let mut src_files: Vec<PathBuf> = Vec::new();
+        if let Err(e) = Config::visit_dirs(src, &mut src_files, false, &IgnoreFile::new()) {
+            return Err(Self::failure(src, e));
+        }
+
+        // split into files and directories
+        let (mut src_files, src_dirs): (Vec<PathBuf>, Vec<PathBuf>) =
+            src_files.into_iter().partition(|f| f.is_file());
+        // render in a fixed order so problems are reported the same way on
+        // every filesystem
+        src_files.sort();
+
+        if self.force == true && dest.exists() == true {
+            // remove everything within the existing destination
+            if let Err(e) = std::fs::remove_dir_all(dest) {
+                return Err(Self::failure(dest, e));
+            }
+        }
+
+        // create the base directory and all directories within it
+        let dest_dirs = src_dirs
+            .iter()
+            .map(|d| dest.join(d.strip_prefix(src).unwrap()));
+        for dir in std::iter::once(dest.to_path_buf()).chain(dest_dirs) {
+            if let Err(e) = std::fs::create_dir_all(&dir) {
+                // remove all intermediate progress
+                return Err(Self::rollback(dest, Self::failure(&dir, e)));
+            }
+        }
+
+        let mut bytes_copied = 0;
+        // keep going after a source has problems so they can all be reported
+        let mut diagnostics = Vec::new();
+
+        for src_file in &src_files {
+            let dest_file = dest.join(src_file.strip_prefix(src).unwrap());
+
+            let resolved = match self.resolve_file(&shells, &per_file, src_file, &dest_file, dest) {
+                Ok(r) => r,
+                Err(d) => return Err(Self::rollback(dest, d)),
+            };
+            match self.copy_file(src_file, &dest_file, &resolved) {
+                Ok(b) => bytes_copied += b,
+                // problems within a source do not stop the others
+                Err(mut d) if d.iter().all(|d| d.path().is_some()) == true => {
+                    diagnostics.append(&mut d)
+                }
+                Err(d) => return Err(Self::rollback(dest, d)),
+            }
+        }
+        match diagnostics.is_empty() {
+            true => Ok(bytes_copied),
+            false => Err(Self::rollback(dest, diagnostics)),
+        }
+    }
+
+    /// Performs the copy operation for a single file using the given shells.
+    fn copy_file(
+        &self,
+        src: &Path,
+        dest: &Path,
+        shells: &ShellMap,
+    ) -> Result<usize, Vec<Diagnostic>> {
+        // open the source for reading
+        let mut reader = match File::open(src) {
+            Ok(r) => BufReader::with_capacity(stream::SNIFF_SIZE, r),
+            Err(e) => {
+                let error = Error::FileRead(src.to_path_buf(), Error::lowerize(e.to_string()));
+                return Err(vec![Diagnostic::from(error)]);
+            }
+        };
+
+        // copy binary files and files marked as verbatim without translating them
+        let encoding = self.settings.encoding_for(src);
+        let (is_binary, format) = match reader.fill_buf() {
+            // a configured encoding means the file is known to be text
+            Ok(bytes) => (
+                encoding.is_none() && stream::is_binary(bytes),
+                Format::detect(bytes, encoding),
+            ),
+            Err(e) => {
+                let error = Error::FileRead(src.to_path_buf(), Error::lowerize(e.to_string()));
+                return Err(vec![Diagnostic::from(error)]);
+            }
+        };
+        let verbatim = is_binary == true || self.settings.is_verbatim(src) == true;
+
+        // write beside the destination and only move the file into place once
+        // it is complete, so a failure never leaves part of it behind
+        let staging = Self::staging(dest);
+        let mut writer = BufWriter::new(self.create(&staging, dest)?);
+        let written = match verbatim {
+            true => {
+                match io::copy(&mut reader, &mut writer).and_then(|b| writer.flush().map(|_| b)) {
+                    Ok(bytes) => {
+                        (self.notify)(Message::Info(format!("copied {:?} verbatim", src)));
+                        Ok(bytes as usize)
+                    }
+                    Err(e) => Err(Self::failure(dest, e)),
+                }
+            }
+            false => self.render_source(src, &mut reader, &mut writer, shells, format),
+        };
+        drop(writer);
+        match written {
+            Ok(bytes) => match std::fs::rename(&staging, dest) {
+                Ok(()) => Ok(bytes),
+                Err(e) => Self::discard(&staging, Self::failure(dest, e)),
+            },
+            Err(diagnostics) => Self::discard(&staging, diagnostics),
+        }
+    }
+
+    /// Renders the text of the source at `src` with the renderer it selects.
+    fn render_source<R: Read, W: Write>(
+        &self,
+        src: &Path,
+        reader: &mut R,
+        writer: &mut W,
+        shells: &ShellMap,
+        mut format: Format,
+    ) -> Result<usize, Vec<Diagnostic>> {
+        // sources can select another template engine in place of koopa's own
+        let name = self
+            .settings
+            .renderer_for(src)
+            .unwrap_or(renderer::KOOPA.to_string());
+        let renderer = match self.renderers.get(&name) {
+            Some(r) => r,
+            None => {
+                let error = Error::RendererUnknown(name);
+                return Err(vec![Diagnostic::from(error).in_file(src)]);
+            }
+        };
+        let delimiters = match self.settings.delimiters_for(src, &self.delimiters) {
+            Ok(d) => d,
+            Err(e) => return Err(vec![Diagnostic::from(e)]),
+        };
+        if let Some(newline) = self.settings.newline_for(src) {
+            format.set_newline(newline);
+        }
+        let ctx = Context {
+            src: src,
+            shells: shells,
+            filters: &self.filters,
+            partials: &self.settings.partials(),
+            delimiters: delimiters,
+            indent: self.settings.indent_for(src, self.indent),
+            format: format,
+            force: self.force,
+            notify: self.notify.as_ref(),
+        };
+        renderer.render_stream(reader, writer, &ctx)
+    }
+
+    /// Creates the file at `path` to write the destination `dest`, creating
+    /// any missing directories when forced.
+    fn create(&self, path: &Path, dest: &Path) -> Result<File, Vec<Diagnostic>> {
+        let base_path = dest.parent().unwrap_or(Path::new("."));
+        match File::create(path) {
+            Ok(w) => Ok(w),
+            Err(e) => match self.force {
+                false => {
+                    let error = Error::DestinationMissingDirectories(base_path.to_path_buf());
+                    Err(vec![Diagnostic::from(error)])
+                }
+                true => match e.kind() == io::ErrorKind::NotFound {
+                    true => {
+                        match std::fs::create_dir_all(base_path).and_then(|_| File::create(path)) {
+                            Ok(w) => Ok(w),
+                            Err(e) => Err(Self::failure(dest, e)),
+                        }
+                    }
+                    false => Err(Self::failure(dest, e)),
+                },
+            },
+        }
+    }
+
+    /// Names the file that the destination `dest` is written to before it is
+    /// moved into place.
+    fn staging(dest: &Path) -> PathBuf {
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        dest.with_file_name(format!(".{}.koopa-tmp", name))
+    }
+
+    /// Removes the partially written file at `dest` before failing with the
+    /// problems.
+    fn discard(dest: &Path, diagnostics: Vec<Diagnostic>) -> Result<usize, Vec<Diagnostic>> {
+        match std::fs::remove_file(dest) {
+            Ok(()) => Err(diagnostics),
+            Err(e) => Err(Self::failure(dest, e)),
+        }
+    }
+
+    /// Adds the shells derived by scripts and then evaluates the shells whose
+    /// values reference other shells.
+    fn resolve(&self, shells: ShellMap) -> Result<ShellMap, Vec<Diagnostic>> {
+        let mut shells = self.derive(shells)?;
+        match shells.resolve(&self.filters, &self.delimiters, self.force) {
+            Ok(()) => Ok(shells),
+            Err(e) => Err(vec![Diagnostic::from(e)]),
+        }
+    }
+
+    /// Adds the shells derived by scripts. A derived shell never replaces a
+    /// shell that is already defined.
+    fn derive(&self, mut shells: ShellMap) -> Result<ShellMap, Vec<Diagnostic>> {
+        for script in &self.scripts {
+            match script.run(&shells) {
+                Ok(derived) => derived.into_iter().for_each(|shell| {
+                    if shells.get(shell.key()).is_none() {
+                        shells.insert(shell);
+                    }
+                }),
+                Err(e) => return Err(vec![Diagnostic::from(e)]),
+            }
+        }
+        Ok(shells)
+    }
+
+    /// Adds the shells derived by scripts and evaluates the shells that are
+    /// the same for every file. The shells built from the path shells of the
+    /// file being written are returned to be evaluated for each file.
+    fn resolve_shared(&self) -> Result<(ShellMap, Computed), Vec<Diagnostic>> {
+        let mut shells = self.derive(self.shells.clone())?;
+        // a path shell that is already defined is the same for every file
+        let path_keys: Vec<Key> = builtin::path_keys()
+            .into_iter()
+            .filter(|key| shells.get(key).is_none())
+            .collect();
+        let mut computed = match shells.computed(&self.delimiters) {
+            Ok(c) => c,
+            Err(e) => return Err(vec![Diagnostic::from(e)]),
+        };
+        let per_file = computed.split_off(&path_keys);
+        match shells.evaluate(&computed, &self.filters, self.force) {
+            Ok(()) => Ok((shells, per_file)),
+            Err(e) => Err(vec![Diagnostic::from(e)]),
+        }
+    }
+
+    /// Adds the path shells of the file being written from `src` to `dest`
+    /// within `root` and evaluates the shells built from them. A path shell
+    /// never replaces a shell that is already defined.
+    fn resolve_file(
+        &self,
+        shells: &ShellMap,
+        per_file: &Computed,
+        src: &Path,
+        dest: &Path,
+        root: &Path,
+    ) -> Result<ShellMap, Vec<Diagnostic>> {
+        let mut shells = shells.clone();
+        match builtin::path_shells(src, dest, root) {
+            Ok(s) => s.into_iter().for_each(|s| {
+                if shells.get(s.key()).is_none() {
+                    shells.insert(s);
+                }
+            }),
+            Err(e) => return Err(vec![Diagnostic::from(e)]),
+        }
+        match shells.evaluate(per_file, &self.filters, self.force) {
+            Ok(()) => Ok(shells),
+            Err(e) => Err(vec![Diagnostic::from(e)]),
+        }
+    }
+
+    /// Verifies the data is allowed to be placed at the destination path.
+    fn permit(&self, dest: &Path) -> Result<(), Vec<Diagnostic>> {
+        match self.force == false && dest.exists() == true {
+            true => Err(vec![Diagnostic::from(Error::DestinationExists(
+                dest.to_path_buf(),
+            ))]),
+            false => Ok(()),
+        }
+    }
+
+    /// Removes everything written to `dest` before failing with the problems.
+    fn rollback(dest: &Path, diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        match std::fs::remove_dir_all(dest) {
+            Ok(()) => diagnostics,
+            Err(e) => Self::failure(dest, e),
+        }
+    }
+
+    fn failure(path: &Path, e: io::Error) -> Vec<Diagnostic> {
+        let error = Error::TranslationFailed(path.to_path_buf(), Error::lowerize(e.to_string()));
+        vec![Diagnostic::from(error)]
+    }
+}
+
+/// Collects the shells and options for an [Engine].
+pub struct EngineBuilder {
+    shells: ShellMap,
+    filters: Filters,
+    renderers: Renderers,
+    scripts: Vec<Script>,
+    settings: Settings,
+    delimiters: Delimiters,
+    indent: Indent,
+    force: bool,
+    notify: Box<dyn Fn(Message)>,
+}
+
+impl Default for EngineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EngineBuilder {
+    /// Starts with no shells, the built-in filters, no settings, and the
+    /// default delimiters and indentation.
+    pub fn new() -> Self {
+        Self {
+            shells: ShellMap::new(),
+            filters: Filters::new(),
+            renderers: Renderers::new(),
+            scripts: Vec::new(),
+            settings: Settings::new(),
+            delimiters: Delimiters::new(),
+            indent: Indent::default(),
+            force: false,
+            notify: Box::new(|_| ()),
+        }
+    }
+
+    /// Sets the shells available to templates, replacing any added before.
+    pub fn shells(mut self, shells: ShellMap) -> Self {
+        self.shells = shells;
+        self
+    }
+
+    /// Adds the shell, collecting its value into a list if the key was
+    /// already added.
+    pub fn shell(mut self, shell: Shell) -> Self {
+        self.shells.append(shell);
+        self
+    }
+
+    pub fn filters(mut self, filters: Filters) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Adds a renderer that sources can select by its name with `renderer` in
+    /// their settings, replacing any renderer with the same name.
+    pub fn renderer<R: Renderer + 'static>(mut self, renderer: R) -> Self {
+        self.renderers.register(renderer);
+        self
+    }
+
+    /// Adds a script that derives shells, which runs after the scripts added
+    /// before it.
+    pub(crate) fn script(mut self, script: Script) -> Self {
+        self.scripts.push(script);
+        self
+    }
+
+    /// Sets the settings loaded from configuration folders, which also decide
+    /// where partials are found.
+    pub(crate) fn settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Sets the delimiters used unless the settings configure others.
+    pub fn delimiters(mut self, delimiters: Delimiters) -> Self {
+        self.delimiters = delimiters;
+        self
+    }
+
+    /// Sets how multi-line values are indented unless the settings configure
+    /// otherwise.
+    pub fn indent(mut self, indent: Indent) -> Self {
+        self.indent = indent;
+        self
+    }
+
+    /// Skips unknown keys instead of reporting them, and allows existing
+    /// destinations to be replaced.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Gives messages about the progress of rendering, such as skipped keys
+    /// and verbatim copies, to `f`. Messages are dropped by default.
+    pub fn on_message<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Message) + 'static,
+    {
+        self.notify = Box::new(f);
+        self
+    }
+
+    pub fn build(self) -> Result<Engine, Error> {
+        if let Err(e) = self.delimiters.validate() {
+            return Err(Error::EngineInvalid(e));
+        }
+        Ok(Engine {
+            shells: self.shells,
+            filters: self.filters,
+            renderers: self.renderers,
+            scripts: self.scripts,
+            settings: self.settings,
+            delimiters: self.delimiters,
+            indent: self.indent,
+            force: self.force,
+            notify: self.notify,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::str::FromStr;
+
+    fn with_shells(pairs: &[(&str, &str)], force: bool) -> Engine {
+        pairs
+            .iter()
+            .fold(Engine::builder(), |b, (k, v)| {
+                b.shell(Shell::with(k.to_string(), v.to_string()))
+            })
+            .force(force)
+            .build()
+            .unwrap()
+    }
+
+    fn errors(result: Result<String, Vec<Diagnostic>>) -> Result<String, Vec<Error>> {
+        result.map_err(|d| d.into_iter().map(|d| d.into_error()).collect())
+    }
+
+    #[test]
+    fn ut_permit_err() {
+        let path = PathBuf::from("README.md");
+        assert_eq!(
+            with_shells(&[], false).permit(&path),
+            Err(vec![Diagnostic::from(Error::DestinationExists(
+                path.clone()
+            ))])
+        );
+    }
+
+    #[test]
+    fn ut_permit_ok() {
+        let path = PathBuf::from("some_unnamed_file.txt.txt");
+        assert_eq!(with_shells(&[], false).permit(&path), Ok(()));
+
+        let path = PathBuf::from("README.md");
+        assert_eq!(with_shells(&[], true).permit(&path), Ok(()));
+    }
+
+    #[test]
+    fn ut_render_str_ok() {
+        let text = "hello {{ koopa.foo }} and {{ koopa.bar }}!";
+        let engine = with_shells(&[("koopa.foo", "world")], true);
+        assert_eq!(
+            engine.render_str(text).unwrap(),
+            "hello world and {{ koopa.bar }}!"
+        );
+
+        let engine = with_shells(&[("koopa.bar", "moon"), ("koopa.foo", "world")], true);
+        assert_eq!(engine.render_str(text).unwrap(), "hello world and moon!");
+    }
+
+    #[test]
+    fn ut_render_str_err() {
+        let engine = with_shells(&[], false);
+        assert_eq!(
+            errors(engine.render_str("hello {{ koopa.foo }}!")),
+            Err(vec![Error::KeyUnknown(
+                Key::from_str("koopa.foo").unwrap(),
+                1,
+                7
+            )])
+        );
+    }
+
+    #[test]
+    fn ut_render_str_multiline_value() {
+        let text = "hello {{ koopa.multi }} and all!";
+        let engine = with_shells(&[("koopa.multi", "earth\nvenus\nmars")], true);
+        assert_eq!(
+            engine.render_str(text).unwrap(),
+            "hello earth
+      venus
+      mars and all!"
+        );
+
+        let engine = with_shells(&[("koopa.multi", "earth\nvenus\nmars\n\n")], true);
+        assert_eq!(
+            engine.render_str(text).unwrap(),
+            "hello earth
+      venus
+      mars
+      
+       and all!"
+        );
+
+        let text = "hello\n{{ koopa.multi }} and all!";
+        let engine = with_shells(&[("koopa.multi", "earth\n venus\nmars\n")], true);
+        assert_eq!(
+            engine.render_str(text).unwrap(),
+            "hello
+earth
+ venus
+mars
+ and all!"
+        );
+    }
+
+    #[test]
+    fn ut_builder_options() {
+        let mut delims = Delimiters::new();
+        delims.set_variable("<%", "%>");
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let sink = messages.clone();
+        let engine = Engine::builder()
+            .shell(Shell::from_str("port=clk").unwrap())
+            .shell(Shell::from_str("port=rst").unwrap())
+            .delimiters(delims)
+            .force(true)
+            .on_message(move |m| sink.borrow_mut().push(m))
+            .build()
+            .unwrap();
+        assert_eq!(
+            engine
+                .render_str("<% koopa.port | join %> {{ koopa.port }} <% koopa.x %>")
+                .unwrap(),
+            "clk, rst {{ koopa.port }} <% koopa.x %>"
+        );
+        assert_eq!(
+            *messages.borrow(),
+            vec![Message::Warning(String::from(
+                "skipping unknown key {{ koopa.x }}"
+            ))]
+        );
+
+        let mut delims = Delimiters::new();
+        delims.set_variable("{%", "%}");
+        assert!(matches!(
+            Engine::builder().delimiters(delims).build(),
+            Err(Error::EngineInvalid(_))
+        ));
+    }
+
+    /// Writes the text backwards to show which renderer was used.
+    struct Reverse;
+
+    impl Renderer for Reverse {
+        fn name(&self) -> &str {
+            "reverse"
+        }
+
+        fn render(&self, text: &str, _: &Context) -> Result<String, String> {
+            match text.contains("{{") {
+                true => Err(String::from("unexpected \"{{\"")),
+                false => Ok(text.trim_end().chars().rev().collect::<String>() + "\n"),
+            }
+        }
+    }
+
+    #[test]
+    fn ut_render_with_renderer() {
+        let root = std::env::temp_dir().join("koopa-ut-renderer");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join(".koopa/src")).unwrap();
+        std::fs::write(
+            root.join(".koopa/koopa.toml"),
+            "[[rules]]\nglob = \"*.rev\"\nrenderer = \"reverse\"\n\n[[rules]]\nglob = \"b.txt\"\nrenderer = \"koopa\"\n",
+        )
+        .unwrap();
+        let src = root.join(".koopa/src");
+        std::fs::write(src.join("a.rev"), "\u{feff}abc\r\n").unwrap();
+        std::fs::write(src.join("b.txt"), "{{ koopa.name }}\n").unwrap();
+
+        let mut settings = Settings::new();
+        settings.add(&Config::new(root.clone()).unwrap());
+        let engine = Engine::builder()
+            .settings(settings.clone())
+            .renderer(Reverse)
+            .build()
+            .unwrap();
+        let dest = root.join("dest");
+        assert!(engine.render_dir(&src, &dest).is_ok());
+        // the output keeps the source's format
+        assert_eq!(
+            std::fs::read_to_string(dest.join("a.rev")).unwrap(),
+            "\u{feff}cba\r\n"
+        );
+        assert_eq!(std::fs::read_to_string(dest.join("b.txt")).unwrap(), "b\n");
+
+        // failures from the renderer belong to the source
+        std::fs::write(src.join("c.rev"), "{{ x }}\n").unwrap();
+        let diagnostics = engine.render_dir(&src, &root.join("dest2")).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].path(), Some(src.join("c.rev").as_path()));
+        assert!(matches!(
+            diagnostics[0].error(),
+            Error::RenderFailed(name, _) if name == "reverse"
+        ));
+
+        // sources cannot select a renderer that is not registered
+        let engine = Engine::builder().settings(settings).build().unwrap();
+        let diagnostics = engine
+            .render_file(&src.join("a.rev"), &root.join("a.rev"))
+            .unwrap_err();
+        assert_eq!(
+            diagnostics[0].error(),
+            &Error::RendererUnknown(String::from("reverse"))
+        );
+        assert!(root.join("a.rev").exists() == false);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ut_render_dir() {
+        let root = std::env::temp_dir().join("koopa-ut-render-dir");
+        let _ = std::fs::remove_dir_all(&root);
+        let src = root.join("src");
+        std::fs::create_dir_all(src.join("rtl")).unwrap();
+        std::fs::write(
+            src.join("rtl/adder.vhd"),
+            "-- {{ koopa.relpath }} in {{ koopa.dir }}\nentity {{ koopa.name }} is\n",
+        )
+        .unwrap();
+        std::fs::write(src.join("top.vhd"), "{{ koopa.top }}\n").unwrap();
+
+        // every problem is reported and nothing is left behind
+        let dest = root.join("dest");
+        let diagnostics = with_shells(&[], false).render_dir(&src, &dest).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].path(), Some(src.join("top.vhd").as_path()));
+        assert!(dest.exists() == false);
+
+        let engine = with_shells(&[("koopa.top", "adder")], false);
+        assert_eq!(engine.render_dir(&src, &dest), Ok(42));
+        assert_eq!(
+            std::fs::read_to_string(dest.join("rtl/adder.vhd")).unwrap(),
+            "-- rtl/adder in rtl\nentity adder is\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dest.join("top.vhd")).unwrap(),
+            "adder\n"
+        );
+
+        // existing destinations are kept unless forced
+        assert_eq!(
+            engine
+                .render_file(&src.join("top.vhd"), &dest.join("top.vhd"))
+                .map_err(|d| d.into_iter().map(|d| d.into_error()).collect::<Vec<_>>()),
+            Err(vec![Error::DestinationExists(dest.join("top.vhd"))])
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ut_render_file_failure_leaves_nothing() {
+        let root = std::env::temp_dir().join("koopa-ut-render-failure");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let (src, dest) = (root.join("top.vhd"), root.join("out.vhd"));
+        std::fs::write(&src, "entity {{ koopa.top }} is\n").unwrap();
+
+        // nothing is written, so the same destination can be tried again
+        assert!(with_shells(&[], false).render_file(&src, &dest).is_err());
+        assert!(dest.exists() == false);
+        assert!(Engine::staging(&dest).exists() == false);
+        let engine = with_shells(&[("koopa.top", "adder")], false);
+        assert_eq!(engine.render_file(&src, &dest), Ok(16));
+
+        // a forced render that fails keeps the existing destination
+        std::fs::write(&src, "{{ koopa.top | nope }}\n").unwrap();
+        let engine = with_shells(&[("koopa.top", "adder")], true);
+        assert!(engine.render_file(&src, &dest).is_err());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "entity adder is\n");
+        assert!(Engine::staging(&dest).exists() == false);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ut_path_shells_do_not_replace() {
+        let root = std::env::temp_dir().join("koopa-ut-path-shells");
+        let _ = std::fs::remove_dir_all(&root);
+        let src = root.join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(
+            src.join("adder.vhd"),
+            "entity {{ koopa.name }} is -- {{ koopa.ext }} {{ koopa.unit }}\n",
+        )
+        .unwrap();
+
+        let engine = with_shells(
+            &[
+                ("koopa.name", "Custom"),
+                ("koopa.unit", "{{ koopa.name }}.{{ koopa.ext }}"),
+            ],
+            false,
+        );
+        engine
+            .render_file(&src.join("adder.vhd"), &root.join("adder.vhd"))
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("adder.vhd")).unwrap(),
+            "entity Custom is -- vhd Custom.vhd\n"
+        );
+        engine.render_dir(&src, &root.join("dest")).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("dest/adder.vhd")).unwrap(),
+            "entity Custom is -- vhd Custom.vhd\n"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -15,31 +15,92 @@ pub enum Error {
     ShellParseMissingEq,
     #[error("failed to koopa file {0:?}: {1}")]
     TranslationFailed(PathBuf, LastError),
+    #[error("failed to stream source: {0}")]
+    StreamFailed(LastError),
+    #[error("could not koopa due to {0} error(s) across {1} file(s)")]
+    SourcesInvalid(usize, usize),
     #[error("unknown key \"{0}\" at line {1} col {2}")]
     KeyUnknown(Key, usize, usize),
     #[error("invalid key \"{0}\" at line {1} col {2}: {3}")]
     KeyInvalid(Key, usize, usize, LastError),
+    #[error("invalid expression \"{0}\" at line {1} col {2}: {3}")]
+    ExprInvalid(String, usize, usize, LastError),
+    #[error("missing closing \"{0}\" for tag at line {1} col {2}")]
+    TagUnclosed(String, usize, usize),
+    #[error("missing closing tag for \"{0}\" block at line {1} col {2}")]
+    BlockUnclosed(String, usize, usize),
+    #[error("unexpected \"{0}\" tag at line {1} col {2}")]
+    BlockUnexpected(String, usize, usize),
+    #[error("invalid filter \"{0}\" at line {1} col {2}: {3}")]
+    FilterInvalid(String, usize, usize, LastError),
+    #[error("unknown filter \"{0}\" at line {1} col {2}")]
+    FilterUnknown(String, usize, usize),
+    #[error("filter \"{0}\" failed at line {1} col {2}: {3}")]
+    FilterFailed(String, usize, usize, LastError),
+    #[error("unknown partial \"{0}\" at line {1} col {2}")]
+    PartialUnknown(String, usize, usize),
+    #[error("partials include each other in a cycle: {0}")]
+    PartialCycle(String),
+    #[error("invalid shell \"{0}\": {1}")]
+    ShellInvalid(String, LastError),
+    #[error("shells reference each other in a cycle: {0}")]
+    ShellCycle(String),
+    #[error("failed to run command \"{0}\": {1}")]
+    CommandFailed(String, LastError),
+    #[error("invalid SOURCE_DATE_EPOCH \"{0}\": expected seconds since 1970-01-01 00:00:00 UTC")]
+    SourceDateEpochInvalid(String),
+    #[error("invalid script {0:?}: {1}")]
+    ScriptInvalid(PathBuf, LastError),
+    #[error("failed to run script {0:?}: {1}")]
+    ScriptFailed(PathBuf, LastError),
+    #[error("cannot run script {0:?} because koopa was built without the \"rhai\" feature")]
+    ScriptUnsupported(PathBuf),
     #[error("key \"{0}\" contains whitespace between characters")]
     KeyContainsWhitespace(String),
     #[error("key \"{0}\" contains newline character")]
     KeyContainsNewline(String),
-    #[error("key \"{0}\" contains too many '.' characters (expected 1)")]
-    KeyContainsMoreDots(String),
-    #[error("key \"{0}\" is not allowed to have a user-defined '.' character")]
-    KeyContainsOneDot(String),
+    #[error("key \"{0}\" contains an empty name between '.' characters")]
+    KeyContainsEmptyName(String),
     #[error("failed to read toml file {0:?}: {1}")]
     TomlParse(PathBuf, LastError),
     #[error("failed to read file {0:?}: {1}")]
     FileRead(PathBuf, LastError),
     #[error("failed to read ignore file {0:?}: {1}")]
     GitIgnoreParse(PathBuf, LastError),
+    #[error("invalid settings in {0:?}: {1}")]
+    SettingsInvalid(PathBuf, LastError),
+    #[error("invalid delimiters for {0:?}: {1}")]
+    DelimitersInvalid(PathBuf, LastError),
+    #[error("unknown renderer \"{0}\"")]
+    RendererUnknown(String),
+    #[error("failed to render with \"{0}\": {1}")]
+    RenderFailed(String, LastError),
+    #[error("invalid engine options: {0}")]
+    EngineInvalid(LastError),
 }
 
 impl Error {
+    /// Returns the line and column of the template tag that caused the error,
+    /// if the error came from a template.
+    pub fn location(&self) -> Option<(usize, usize)> {
+        match self {
+            Self::KeyUnknown(_, line, col)
+            | Self::KeyInvalid(_, line, col, _)
+            | Self::ExprInvalid(_, line, col, _)
+            | Self::TagUnclosed(_, line, col)
+            | Self::BlockUnclosed(_, line, col)
+            | Self::BlockUnexpected(_, line, col)
+            | Self::FilterInvalid(_, line, col, _)
+            | Self::FilterUnknown(_, line, col)
+            | Self::FilterFailed(_, line, col, _)
+            | Self::PartialUnknown(_, line, col) => Some((*line, *col)),
+            _ => None,
+        }
+    }
+
     // Presents the message `s` without the first letter being capitalized.
     pub fn lowerize(s: String) -> String {
         s.char_indices()
-            .into_iter()
             .map(|(i, c)| if i == 0 { c.to_ascii_lowercase() } else { c })
             .collect()
     }
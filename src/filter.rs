@@ -0,0 +1,431 @@
+use crate::shell::Value;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, NaiveDate, NaiveTime};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// The name of the filter that provides a fallback for missing or empty values.
+pub const DEFAULT: &str = "default";
+
+/// The signature of a function that transforms a value, given any arguments
+/// written after the filter's name.
+pub type Filter = Rc<dyn Fn(&Value, &[Value]) -> Result<Value, String>>;
+
+/// A filter applied to a placeholder's value, such as `replace("-", "_")`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FilterCall {
+    name: String,
+    args: Vec<Value>,
+}
+
+impl FilterCall {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn args(&self) -> &[Value] {
+        &self.args
+    }
+
+    /// Parses the text of a single filter, which is a name optionally followed
+    /// by a parenthesized list of quoted or numeric arguments.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (name, rest) = match s.find('(') {
+            Some(i) => (s[..i].trim(), Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        if name.is_empty() == true
+            || name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') == false
+        {
+            return Err(format!("invalid filter name \"{}\"", name));
+        }
+        let args = match rest {
+            Some(rest) => match rest.trim_end().strip_suffix(')') {
+                Some(inner) => Self::parse_args(inner)?,
+                None => return Err(String::from("missing closing ')'")),
+            },
+            None => Vec::new(),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            args: args,
+        })
+    }
+
+    /// Parses the fallback written after a '?' as a call to the default filter.
+    pub fn parse_default(s: &str) -> Result<Self, String> {
+        match Self::parse_args(s)?.as_slice() {
+            [v] => Ok(Self {
+                name: String::from(DEFAULT),
+                args: vec![v.clone()],
+            }),
+            _ => Err(String::from(
+                "expected a single quoted or numeric fallback value",
+            )),
+        }
+    }
+
+    fn parse_args(s: &str) -> Result<Vec<Value>, String> {
+        let mut args = Vec::new();
+        let mut chars = s.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            match chars.next() {
+                None if args.is_empty() == true => break,
+                None => return Err(String::from("expected an argument after ','")),
+                Some(q) if q == '"' || q == '\'' => {
+                    let mut word = String::new();
+                    loop {
+                        match chars.next() {
+                            Some(c) if c == q => break,
+                            Some(c) => word.push(c),
+                            None => return Err(String::from("unterminated string literal")),
+                        }
+                    }
+                    args.push(Value::from(word));
+                }
+                Some(c) => {
+                    let mut word = String::from(c);
+                    while let Some(c) = chars.next_if(|c| *c != ',' && c.is_whitespace() == false) {
+                        word.push(c);
+                    }
+                    if word.parse::<f64>().is_err() {
+                        return Err(format!(
+                            "expected a quoted or numeric argument but found \"{}\"",
+                            word
+                        ));
+                    }
+                    args.push(Value::from(word));
+                }
+            }
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            match chars.next() {
+                Some(',') => (),
+                None => break,
+                Some(c) => return Err(format!("unexpected character '{}' in arguments", c)),
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Splits the contents of a placeholder on every '|' that is not within a
+/// quoted string.
+pub fn split_pipes(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '|' => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            None => (),
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// The collection of filters available to placeholders.
+#[derive(Clone)]
+pub struct Filters {
+    inner: HashMap<String, Filter>,
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Filters {
+    /// Creates the collection with all the built-in filters.
+    pub fn new() -> Self {
+        let mut filters = Self {
+            inner: HashMap::new(),
+        };
+        filters.register_text("upper", |s| s.to_uppercase());
+        filters.register_text("lower", |s| s.to_lowercase());
+        filters.register_text("trim", |s| s.trim().to_string());
+        filters.register_text("capitalize", |s| {
+            let mut chars = s.chars();
+            match chars.next() {
+                Some(c) => c.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        });
+        filters.register_text("snake", |s| join_words(s, "_", str::to_lowercase));
+        filters.register_text("upper_snake", |s| join_words(s, "_", str::to_uppercase));
+        filters.register_text("kebab", |s| join_words(s, "-", str::to_lowercase));
+        filters.register_text("pascal", |s| join_words(s, "", capitalize_word));
+        filters.register_text("camel", |s| {
+            let pascal = join_words(s, "", capitalize_word);
+            let mut chars = pascal.chars();
+            match chars.next() {
+                Some(c) => c.to_lowercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        });
+        filters.register("replace", |value, args| match args {
+            [from, to] => {
+                let (from, to) = (from.to_text(), to.to_text());
+                Ok(map_items(value, |s| s.replace(from.as_ref(), &to)))
+            }
+            _ => Err(format!("expected 2 arguments but found {}", args.len())),
+        });
+        filters.register(DEFAULT, |value, args| match args {
+            [fallback] => match value {
+                Value::Str(s) if s.is_empty() == true => Ok(fallback.clone()),
+                Value::List(items) if items.is_empty() == true => Ok(fallback.clone()),
+                _ => Ok(value.clone()),
+            },
+            _ => Err(format!("expected 1 argument but found {}", args.len())),
+        });
+        filters.register("join", |value, args| {
+            let sep = match args {
+                [] => String::from(", "),
+                [sep] => sep.to_string(),
+                _ => {
+                    return Err(format!(
+                        "expected at most 1 argument but found {}",
+                        args.len()
+                    ))
+                }
+            };
+            Ok(Value::from(value.items().join(&sep)))
+        });
+        filters.register("date", |value, args| match args {
+            [format] => {
+                let format = format.to_text();
+                let items: Vec<Item> = StrftimeItems::new(&format).collect();
+                if items.iter().any(|i| i == &Item::Error) == true {
+                    return Err(format!("invalid date format \"{}\"", format));
+                }
+                let formatted: Result<Vec<String>, String> = value
+                    .items()
+                    .into_iter()
+                    .map(|s| format_date(s, &items))
+                    .collect();
+                match value {
+                    Value::Str(_) => Ok(Value::from(formatted?.remove(0))),
+                    Value::List(_) => Ok(Value::from(formatted?)),
+                }
+            }
+            _ => Err(format!("expected 1 argument but found {}", args.len())),
+        });
+        filters
+    }
+
+    /// Adds the filter under the given name, replacing any existing filter with
+    /// the same name.
+    pub fn register<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&Value, &[Value]) -> Result<Value, String> + 'static,
+    {
+        self.inner.insert(name.to_string(), Rc::new(f));
+    }
+
+    /// Adds a filter that transforms each item of a value and takes no
+    /// arguments.
+    fn register_text(&mut self, name: &'static str, f: fn(&str) -> String) {
+        self.register(name, move |value, args| match args.is_empty() {
+            true => Ok(map_items(value, f)),
+            false => Err(format!("expected 0 arguments but found {}", args.len())),
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Filter> {
+        self.inner.get(name)
+    }
+
+    /// Lists the names of every registered filter in alphabetical order.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.inner.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        names
+    }
+}
+
+impl std::fmt::Debug for Filters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Filters")
+            .field("inner", &self.names())
+            .finish()
+    }
+}
+
+/// Applies `f` to a single value or to every item of a list.
+fn map_items<F: Fn(&str) -> String>(value: &Value, f: F) -> Value {
+    match value {
+        Value::Str(s) => Value::from(f(s)),
+        Value::List(items) => Value::from(items.iter().map(|s| f(s)).collect::<Vec<String>>()),
+    }
+}
+
+/// Writes the date, time, or both held by `s` in the given strftime format.
+/// The text may be an RFC 3339 timestamp, a date such as "2024-05-01", or a
+/// time such as "13:45:00".
+fn format_date(s: &str, items: &[Item]) -> Result<String, String> {
+    let mut result = String::new();
+    let written = if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        write!(result, "{}", t.format_with_items(items.iter()))
+    } else if let Ok(d) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        write!(result, "{}", d.format_with_items(items.iter()))
+    } else if let Ok(t) = NaiveTime::parse_from_str(s, "%H:%M:%S") {
+        write!(result, "{}", t.format_with_items(items.iter()))
+    } else {
+        return Err(format!("expected a date or time but found \"{}\"", s));
+    };
+    match written {
+        Ok(()) => Ok(result),
+        Err(_) => Err(format!("format does not apply to \"{}\"", s)),
+    }
+}
+
+fn capitalize_word(s: &str) -> String {
+    let lower = s.to_lowercase();
+    let mut chars = lower.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Converts each word in `s` with `f` and joins them back together with `sep`.
+fn join_words(s: &str, sep: &str, f: fn(&str) -> String) -> String {
+    split_words(s)
+        .iter()
+        .map(|w| f(w))
+        .collect::<Vec<String>>()
+        .join(sep)
+}
+
+/// Breaks the text into words on any non-alphanumeric character and at
+/// changes in case, such that "HTTPServer-v2" becomes "HTTP", "Server", "v2".
+fn split_words(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let chars: Vec<char> = s.chars().collect();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_alphanumeric() == false {
+            if word.is_empty() == false {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if let Some(prev) = word.chars().last() {
+            let next = chars.get(i + 1);
+            // a lowercase letter followed by an uppercase letter ("myName"), or
+            // the last uppercase letter of an acronym ("HTTPServer")
+            let boundary = (prev.is_lowercase() && c.is_uppercase())
+                || (prev.is_uppercase()
+                    && c.is_uppercase()
+                    && next.is_some_and(|n| n.is_lowercase()));
+            if boundary == true {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.push(c);
+    }
+    if word.is_empty() == false {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(name: &str, s: &str, args: &[&str]) -> Result<String, String> {
+        let args: Vec<Value> = args.iter().map(|a| Value::from(*a)).collect();
+        let f = Filters::new().get(name).unwrap().clone();
+        Ok(f(&Value::from(s), &args)?.to_string())
+    }
+
+    #[test]
+    fn ut_case_filters() {
+        assert_eq!(
+            apply("snake", "myFifo-ctrl HTTPServer", &[]).unwrap(),
+            "my_fifo_ctrl_http_server"
+        );
+        assert_eq!(
+            apply("upper_snake", "src/fifo.h", &[]).unwrap(),
+            "SRC_FIFO_H"
+        );
+        assert_eq!(
+            apply("kebab", "MyProject_v2", &[]).unwrap(),
+            "my-project-v2"
+        );
+        assert_eq!(apply("pascal", "my-project", &[]).unwrap(), "MyProject");
+        assert_eq!(apply("camel", "my_project", &[]).unwrap(), "myProject");
+        assert_eq!(apply("upper", "koopa", &[]).unwrap(), "KOOPA");
+        assert_eq!(apply("capitalize", "koopa", &[]).unwrap(), "Koopa");
+        assert_eq!(apply("trim", "  koopa\n", &[]).unwrap(), "koopa");
+    }
+
+    #[test]
+    fn ut_replace_filter() {
+        assert_eq!(apply("replace", "a-b-c", &["-", "_"]).unwrap(), "a_b_c");
+        assert!(apply("replace", "a-b-c", &["-"]).is_err());
+        assert!(apply("upper", "a", &["-"]).is_err());
+    }
+
+    #[test]
+    fn ut_date_filter() {
+        let t = "2023-11-14T22:13:20Z";
+        assert_eq!(
+            apply("date", t, &["%B %-d, %Y"]).unwrap(),
+            "November 14, 2023"
+        );
+        assert_eq!(apply("date", t, &["%Y%m%d-%H%M"]).unwrap(), "20231114-2213");
+        assert_eq!(
+            apply("date", "2023-11-14", &["%d/%m/%y"]).unwrap(),
+            "14/11/23"
+        );
+        assert_eq!(
+            apply("date", "22:13:20", &["%I:%M %p"]).unwrap(),
+            "10:13 PM"
+        );
+        assert!(apply("date", "2023-11-14", &["%H:%M"]).is_err());
+        assert!(apply("date", "today", &["%Y"]).is_err());
+        assert!(apply("date", t, &["%Q"]).is_err());
+        assert!(apply("date", t, &[]).is_err());
+    }
+
+    #[test]
+    fn ut_parse_filter_call() {
+        assert_eq!(
+            FilterCall::parse(" replace(\"-\", '_') "),
+            Ok(FilterCall {
+                name: String::from("replace"),
+                args: vec![Value::from("-"), Value::from("_")],
+            })
+        );
+        assert_eq!(
+            FilterCall::parse("upper"),
+            Ok(FilterCall {
+                name: String::from("upper"),
+                args: Vec::new(),
+            })
+        );
+        assert!(FilterCall::parse("replace(\"-\"").is_err());
+        assert!(FilterCall::parse("replace(a, b)").is_err());
+        assert!(FilterCall::parse("up per").is_err());
+    }
+
+    #[test]
+    fn ut_split_pipes() {
+        assert_eq!(
+            split_pipes(" koopa.x | replace(\"|\", '|') | upper"),
+            vec![" koopa.x ", " replace(\"|\", '|') ", " upper"]
+        );
+    }
+}
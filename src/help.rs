@@ -14,6 +14,7 @@ Options:
     --shell, -s <key=value>...  specify runtime in-line text replacements
     --ignore-work               ignore .koopa folders along the working path
     --ignore-home               ignore the .koopa folder at the home path
+    --allow-commands            run commands for shells outside the home folder
     --force                     bypass safety checks and errors
     --verbose                   use verbose output
     --list                      list available files + shells and exit
@@ -42,3 +43,25 @@ pub fn info(msg: String, verbose: bool) {
         println!("info: {}", msg);
     }
 }
+
+/// Displays the diagnostic `frame` to stderr, followed by a blank line.
+pub fn diagnostic(frame: String) {
+    eprintln!("{}", frame);
+}
+
+/// A message about the progress of an operation that is not a problem.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Info(String),
+    Warning(String),
+}
+
+impl Message {
+    /// Displays the message to stdout if `verbose` is set.
+    pub fn show(&self, verbose: bool) {
+        match self {
+            Self::Info(msg) => info(msg.clone(), verbose),
+            Self::Warning(msg) => warning(msg.clone(), verbose),
+        }
+    }
+}
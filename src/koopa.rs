@@ -3,12 +3,15 @@
 use super::error::Error;
 use super::help;
 use super::shell::{Shell, ShellMap};
-use crate::config::{Config, IgnoreFile};
-use crate::shell::{self, Key};
+use crate::builtin;
+use crate::command::Commands;
+use crate::config::{Config, Settings};
+use crate::engine::Engine;
+use crate::script::Script;
+use crate::shell::{Key, Value};
 use cliproc::{cli, proc, stage::*};
 use cliproc::{Arg, Cli, Command, Help};
-use std::collections::HashMap;
-use std::io;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -24,6 +27,7 @@ pub struct Koopa {
     list: bool,
     ignore_home: bool,
     ignore_work: bool,
+    allow_commands: bool,
     no_args: bool,
     shells: Vec<Shell>,
 }
@@ -53,6 +57,7 @@ impl Command for Koopa {
             list: cli.check(Arg::flag("list"))?,
             ignore_work: cli.check(Arg::flag("ignore-work"))?,
             ignore_home: cli.check(Arg::flag("ignore-home"))?,
+            allow_commands: cli.check(Arg::flag("allow-commands"))?,
             shells: cli
                 .get_all(Arg::option("shell").switch('s').value("key=value"))?
                 .unwrap_or_default(),
@@ -85,15 +90,16 @@ impl Command for Koopa {
 
         let mut shells = ShellMap::new();
 
-        // start with the standard shells (blue shells)
-        if self.list == false {
-            shells.merge(ShellMap::from(&vec![Shell::with(
-                format!("{}{}", shell::KEY_PREFIX, "name"),
-                Self::find_filename(&self.dest)?,
-            )]));
-        }
+        // start with the standard shells (blue shells), where the engine adds
+        // the shells that describe each file's path
+        shells.merge(ShellMap::from(&builtin::time_shells(&builtin::now()?)));
 
         let mut koopa_sources: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let mut settings = Settings::new();
+        let mut scripts: Vec<PathBuf> = Vec::new();
+        let mut commands = Commands::new();
+        // shells whose commands are shown rather than run
+        let mut deferred: HashMap<Key, String> = HashMap::new();
 
         // load configurations and shells from files (red shells)
         {
@@ -106,8 +112,17 @@ impl Command for Koopa {
                     if let Some(name) = home_config.resolve_source(&self.src) {
                         resolved_src = name;
                     }
-                    shells.merge(ShellMap::from(&home_config.get_shells()));
-                    koopa_sources.extend(home_config.get_sources().into_iter());
+                    // the home folder belongs to the user, so its commands are trusted
+                    self.add_shells(
+                        &home_config,
+                        true,
+                        &mut shells,
+                        &mut commands,
+                        &mut deferred,
+                    )?;
+                    koopa_sources.extend(home_config.get_sources());
+                    settings.add(&home_config);
+                    scripts.extend(home_config.get_script());
                 }
             }
 
@@ -124,8 +139,16 @@ impl Command for Koopa {
                     if let Some(name) = work_config.resolve_source(&self.src) {
                         resolved_src = name;
                     }
-                    shells.merge(ShellMap::from(&work_config.get_shells()));
-                    koopa_sources.extend(work_config.get_sources().into_iter());
+                    self.add_shells(
+                        &work_config,
+                        self.allow_commands,
+                        &mut shells,
+                        &mut commands,
+                        &mut deferred,
+                    )?;
+                    koopa_sources.extend(work_config.get_sources());
+                    settings.add(&work_config);
+                    scripts.extend(work_config.get_script());
                 }
             }
 
@@ -140,8 +163,21 @@ impl Command for Koopa {
             }
         }
 
-        // load shells from command-line (green shells)
-        shells.merge(ShellMap::from(&self.shells));
+        // load shells from command-line (green shells), where repeated keys form a list
+        let mut green_shells = ShellMap::new();
+        self.shells
+            .iter()
+            .for_each(|shell| green_shells.append(shell.clone()));
+        shells.merge(green_shells);
+
+        // fill in the user's identity where no other shell defines it (blue shells)
+        let mut origins: HashMap<Key, &str> = HashMap::new();
+        for (shell, origin) in builtin::identity_shells(&std::env::current_dir()?) {
+            if shells.get(shell.key()).is_none() && deferred.contains_key(shell.key()) == false {
+                origins.insert(shell.key().clone(), origin);
+                shells.insert(shell);
+            }
+        }
 
         if self.list == true {
             println!("Sources:");
@@ -152,439 +188,172 @@ impl Command for Koopa {
                 arr
             };
             key_order.iter().for_each(|&k| {
+                let path = koopa_sources.get(k).unwrap();
                 println!(
-                    "({}) {} -> {:?}",
-                    if koopa_sources.get(k).unwrap().is_file() {
-                        "f"
-                    } else {
-                        "d"
-                    },
+                    "({}) {} -> {:?}{}",
+                    if path.is_file() { "f" } else { "d" },
                     k.display(),
-                    koopa_sources.get(k).unwrap()
+                    path,
+                    // note sources that select a renderer
+                    match settings.renderer_for(path) {
+                        Some(name) if path.is_file() => {
+                            format!(" [{}]", name)
+                        }
+                        _ => String::new(),
+                    }
                 )
             });
             println!();
             println!("Shells:");
-            // print the shells
+            // print the shells, where shells within a namespace are indented below it
             let key_order: Vec<&Key> = {
-                let mut arr: Vec<&Key> = shells.inner().keys().collect();
-                arr.sort();
+                let mut arr: Vec<&Key> = shells.inner().keys().chain(deferred.keys()).collect();
+                arr.sort_by(|a, b| a.get_parts().cmp(&b.get_parts()));
                 arr
             };
-            key_order
-                .iter()
-                .for_each(|&k| println!("(*) {} -> \"{}\"", k.get_name(), shells.get(k).unwrap()));
+            let mut last: Vec<&str> = Vec::new();
+            key_order.iter().for_each(|&k| {
+                let parts = k.get_parts();
+                let (name, namespaces) = parts.split_last().unwrap();
+                let shared = last
+                    .iter()
+                    .zip(namespaces.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                namespaces[shared..]
+                    .iter()
+                    .enumerate()
+                    .for_each(|(i, ns)| println!("{}(*) {}", "    ".repeat(shared + i), ns));
+                let indent = "    ".repeat(namespaces.len());
+                // note built-in shells that were found elsewhere
+                let origin = match origins.get(k) {
+                    Some(origin) => format!(" [{}]", origin),
+                    None => String::new(),
+                };
+                match deferred.get(k) {
+                    Some(cmd) => println!("{}(*) {} -> $({})", indent, name, cmd),
+                    None => match shells.get(k).unwrap() {
+                        Value::List(items) => {
+                            println!("{}(*) {} -> {:?}{}", indent, name, items, origin)
+                        }
+                        value => println!("{}(*) {} -> \"{}\"{}", indent, name, value, origin),
+                    },
+                }
+                last = parts;
+            });
             println!();
             return Ok(());
         }
 
         // run the command
-        self.run(shells)
+        self.run(shells, settings, scripts)
     }
 }
 
 impl Koopa {
-    fn run(&self, mut shells: ShellMap) -> Result<(), AnyError> {
-        // ensure the data is allowed to be moved to the destination
-        Self::has_permission(&self.dest, self.force)?;
-
-        // perform the copy operation
-        let bytes_copied = match self.src.is_file() {
-            true => Self::copy_file(&self.src, &self.dest, &shells, self.force, self.verbose)?,
-            false => Self::copy_dir(&self.src, &self.dest, &mut shells, self.force, self.verbose)?,
-        };
-
-        // provide information back to the user that the operation was a success
-        help::info(
-            format!(
-                "successfully koopa'ed {} bytes to {:?}",
-                bytes_copied, self.dest
-            ),
-            self.verbose,
-        );
-        Ok(())
-    }
-
-    /// Performs the copy operation for a directory. If the function fails,
-    /// no files will be available.
-    fn copy_dir(
-        src: &PathBuf,
-        dest: &PathBuf,
+    /// Merges the shells of a configuration layer into `shells`, running the
+    /// commands of shells that take their value from one when the layer is
+    /// `trusted`.
+    ///
+    /// When listing, commands are not run and are instead recorded in
+    /// `deferred` so they can be shown.
+    fn add_shells(
+        &self,
+        config: &Config,
+        trusted: bool,
         shells: &mut ShellMap,
-        force: bool,
-        verbose: bool,
-    ) -> Result<usize, AnyError> {
-        // get all the sources
-        let mut src_files: Vec<PathBuf> = Vec::new();
-
-        match Config::visit_dirs(&src.as_path(), &mut src_files, false, &IgnoreFile::new()) {
-            Ok(_) => (),
-            Err(e) => return Err(Box::new(e))?,
-        }
-
-        // split into files and directories
-        let (src_files, src_dirs): (Vec<PathBuf>, Vec<PathBuf>) =
-            src_files.into_iter().partition(|f| f.is_file());
-
-        if force == true && dest.exists() == true {
-            // remove everything within the existing destintation
-            match std::fs::remove_dir_all(&dest) {
-                Ok(_) => (),
-                Err(e) => return Err(Box::new(e))?,
-            }
-        }
-
-        // create the list of file destinations
-        let mut dest_files = Vec::new();
-        src_files
-            .iter()
-            .for_each(|f| dest_files.push(dest.join(f.strip_prefix(src).unwrap())));
-
-        // create the list of directory destinations
-        let mut dest_dirs = Vec::new();
-        src_dirs
-            .iter()
-            .for_each(|f| dest_dirs.push(dest.join(f.strip_prefix(src).unwrap())));
-
-        // create base directory
-        match std::fs::create_dir_all(&dest) {
-            Ok(_) => (),
-            Err(e) => {
-                // remove all intermediate progress
-                match std::fs::remove_dir_all(&dest) {
-                    Ok(_) => return Err(Box::new(e))?,
-                    Err(e) => return Err(Box::new(e))?,
-                }
-            }
-        }
-
-        // create all directories
-        for dir in &dest_dirs {
-            // create any missing directories for destination
-            match std::fs::create_dir_all(&dir) {
-                Ok(_) => (),
-                Err(e) => {
-                    // remove all intermediate progress
-                    match std::fs::remove_dir_all(&dest) {
-                        Ok(_) => return Err(Box::new(e))?,
-                        Err(e) => return Err(Box::new(e))?,
-                    }
-                }
+        commands: &mut Commands,
+        deferred: &mut HashMap<Key, String>,
+    ) -> Result<(), Error> {
+        let values = config.get_shells();
+        values.iter().for_each(|s| {
+            deferred.remove(s.key());
+        });
+        shells.merge(ShellMap::from(&values));
+
+        for (key, cmd) in config.get_commands() {
+            // a shell from the command-line replaces the command's value anyway
+            if self.shells.iter().any(|s| s.key() == &key) == true {
+                continue;
             }
-        }
-
-        let mut bytes_copied = 0;
-
-        for i in 0..src_files.len() {
-            let src_file = src_files.get(i).unwrap();
-            let dest_file = dest_files.get(i).unwrap();
-
-            // set koopa.name for each file
-            shells.merge(ShellMap::from(&vec![Shell::with(
-                format!("{}{}", shell::KEY_PREFIX, "name"),
-                Self::find_filename(dest_file).unwrap(),
-            )]));
-
-            bytes_copied += match Self::copy_file(&src_file, &dest_file, &shells, force, verbose) {
-                Ok(b) => b,
-                Err(e) => {
-                    // remove all intermediate progress
-                    match std::fs::remove_dir_all(&dest) {
-                        Ok(_) => return Err(e),
-                        Err(e) => return Err(Box::new(e))?,
-                    }
-                }
+            if self.list == true {
+                shells.remove(&key);
+                deferred.insert(key, cmd);
+            } else if trusted == false {
+                help::warning(
+                    format!(
+                        "skipping command for shell {} from {:?}; use --allow-commands to run it",
+                        key.get_name(),
+                        config.get_root()
+                    ),
+                    self.verbose,
+                );
+            } else {
+                let value = commands.run(&cmd)?;
+                shells.insert(Shell::from((key, Value::from(value))));
             }
         }
-        Ok(bytes_copied)
+        Ok(())
     }
 
-    /// Attempts to acquire the string of the file name, minus its extension.
-    fn find_filename(p: &PathBuf) -> Result<String, Error> {
-        if let Some(p) = p.file_name() {
-            if let Some(p) = p.to_str() {
-                return Ok(String::from(p.split('.').into_iter().next().unwrap()));
+    fn run(
+        &self,
+        shells: ShellMap,
+        settings: Settings,
+        scripts: Vec<PathBuf>,
+    ) -> Result<(), AnyError> {
+        let verbose = self.verbose;
+        let mut builder = Engine::builder()
+            .shells(shells)
+            .settings(settings)
+            .force(self.force)
+            .on_message(move |m| m.show(verbose));
+        // scripts run in the same order their folders were loaded
+        for path in scripts {
+            // a script must not stop runs that never use it in builds that
+            // cannot run scripts
+            if cfg!(feature = "rhai") == false {
+                help::warning(
+                    format!(
+                        "skipping script {:?}; koopa was built without the \"rhai\" feature",
+                        path
+                    ),
+                    self.verbose,
+                );
+                continue;
             }
+            builder = builder.script(Script::load(&path)?);
         }
-        Err(Error::DestinationMissingFileName(p.clone()))
-    }
+        let engine = builder.build()?;
 
-    /// Peforms the copy operation, moving bytes from `src` to `dest` while replacing
-    /// any known variables with their corresponding values.
-    fn copy_file(
-        src: &PathBuf,
-        dest: &PathBuf,
-        shells: &ShellMap,
-        force: bool,
-        verbose: bool,
-    ) -> Result<usize, AnyError> {
-        // read contents from source
-        let read_words = match std::fs::read_to_string(&src) {
-            Ok(r) => r,
-            Err(e) => return Err(Error::FileRead(src.clone(), Error::lowerize(e.to_string())))?,
-        };
-        // translate any variables within the text
-        let write_words = match Self::translate(&read_words, shells, force, verbose) {
-            Ok(r) => r,
-            Err(e) => {
-                return Err(Error::TranslationFailed(
-                    src.clone(),
-                    Error::lowerize(e.to_string()),
-                ))?
-            }
+        // perform the copy operation
+        let result = match self.src.is_file() {
+            true => engine.render_file(&self.src, &self.dest),
+            false => engine.render_dir(&self.src, &self.dest),
         };
-
-        let working_path = Path::new(".");
-        let base_path = dest.parent().unwrap_or(&working_path);
-
-        // place the contents at the destination
-        match std::fs::write(&dest, &write_words) {
-            Ok(_) => (),
-            Err(e) => match force {
-                false => {
-                    return Err(Box::new(Error::DestinationMissingDirectories(
-                        base_path.to_path_buf(),
-                    )))
+        let bytes_copied = match result {
+            Ok(b) => b,
+            Err(mut diagnostics) => {
+                // a problem outside of any source is reported on its own
+                if let Some(i) = diagnostics.iter().position(|d| d.path().is_none()) {
+                    return Err(diagnostics.swap_remove(i).into_error())?;
                 }
-                true => {
-                    if e.kind() == io::ErrorKind::NotFound {
-                        std::fs::create_dir_all(base_path)?;
-                        std::fs::write(&dest, &write_words)?;
-                    } else {
-                        return Err(Box::new(e));
-                    }
-                }
-            },
-        }
-        Ok(write_words.len())
-    }
-
-    /// Verifies the data is allowed to be placed at the destination path.
-    fn has_permission(path: &PathBuf, ignore: bool) -> Result<(), Error> {
-        match ignore == false && path.exists() == true {
-            true => Err(Error::DestinationExists(path.clone())),
-            false => Ok(()),
-        }
-    }
-
-    /// Translates the string contents `text` with variable replacement.
-    fn translate(
-        text: &str,
-        shells: &ShellMap,
-        force: bool,
-        verbose: bool,
-    ) -> Result<String, Error> {
-        enum State {
-            Normal,
-            L1,
-            Replace,
-            R1,
-        }
-
-        let mut result = String::with_capacity(text.len());
-        let mut key = Key::new();
-        let mut state = State::Normal;
-
-        let mut stream = text.char_indices();
-        let mut line_no: usize = 1;
-        let mut col_no: usize = 1;
-        let mut last_linebreak: Option<isize> = None;
-        while let Some((i, c)) = stream.next() {
-            // state transitions
-            if c == '\n' {
-                line_no += 1;
-                last_linebreak = Some(i as isize);
-            }
-            match state {
-                State::Normal => {
-                    result.push(c);
-                    if c == '{' {
-                        col_no = (i as isize - last_linebreak.unwrap_or(-1)) as usize;
-                        state = State::L1
-                    }
-                }
-                State::L1 => match c {
-                    '{' => {
-                        result.pop();
-                        state = State::Replace;
-                    }
-                    _ => {
-                        result.push(c);
-                        state = State::Normal;
-                    }
-                },
-                State::Replace => {
-                    key.push(c);
-                    if c == '}' {
-                        state = State::R1
-                    }
+                for d in &diagnostics {
+                    help::diagnostic(d.frame(&self.src));
                 }
-                State::R1 => match c {
-                    '}' => {
-                        key.pop();
-                        if key.is_koopa_key() == true {
-                            // make sure this key being read is valid
-                            if let Some(e) = key.validate() {
-                                return Err(Error::KeyInvalid(
-                                    key.clone(),
-                                    line_no,
-                                    col_no,
-                                    Error::lowerize(e.to_string()),
-                                ));
-                            }
-                        }
-                        // replace the variable with its value
-                        match shells.get(&key) {
-                            // multi-line values should maintain the same indentation
-                            Some(val) => {
-                                let indentation = if col_no == 0 { 0 } else { col_no - 1 };
-                                let mut lines = val.as_str().split('\n');
-                                result.push_str(lines.next().unwrap());
-                                while let Some(line) = lines.next() {
-                                    result.push_str(&format!(
-                                        "\n{}{}",
-                                        (0..indentation).map(|_| " ").collect::<String>(),
-                                        line
-                                    ));
-                                }
-                            }
-                            None => {
-                                // make sure we know this is a missing key if recognized
-                                if key.is_koopa_key() == true {
-                                    if force == false {
-                                        return Err(Error::KeyUnknown(
-                                            key.clone(),
-                                            line_no,
-                                            col_no,
-                                        ));
-                                    } else {
-                                        help::warning(
-                                            format!("skipping unknown key {}", key),
-                                            verbose,
-                                        );
-                                    }
-                                }
-                                result.push_str(&key.to_string())
-                            }
-                        }
-                        // clean up the contents stored in the variable
-                        key.clear();
-                        state = State::Normal;
-                    }
-                    _ => {
-                        key.push(c);
-                        state = State::Replace;
-                    }
-                },
+                let files: HashSet<Option<&Path>> = diagnostics.iter().map(|d| d.path()).collect();
+                return Err(Error::SourcesInvalid(diagnostics.len(), files.len()))?;
             }
-        }
-        Ok(result)
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::str::FromStr;
-
-    use super::*;
-
-    #[test]
-    fn ut_has_permission_err() {
-        let path = PathBuf::from("README.md");
-        assert_eq!(
-            Err(Error::DestinationExists(path.clone())),
-            Koopa::has_permission(&path, false)
-        );
-    }
-
-    #[test]
-    fn ut_has_permission_ok() {
-        let path = PathBuf::from("some_unnamed_file.txt.txt");
-        assert_eq!(Ok(()), Koopa::has_permission(&path, false));
-
-        let path = PathBuf::from("README.md");
-        assert_eq!(Ok(()), Koopa::has_permission(&path, true));
-    }
-
-    #[test]
-    fn ut_translate_text_ok() {
-        let text = "hello {{ koopa.foo }} and {{ koopa.bar }}!";
-        let mut shells = ShellMap::new();
-        shells.insert(Shell::with(
-            String::from("koopa.foo"),
-            String::from("world"),
-        ));
-        assert_eq!(
-            Koopa::translate(text, &shells, true, false).unwrap(),
-            "hello world and {{ koopa.bar }}!"
-        );
-
-        let text = "hello {{ koopa.foo }} and {{ koopa.bar }}!";
-        let mut shells = ShellMap::new();
-        shells.insert(Shell::with(String::from("koopa.bar"), String::from("moon")));
-        shells.insert(Shell::with(
-            String::from("koopa.foo"),
-            String::from("world"),
-        ));
-        assert_eq!(
-            Koopa::translate(text, &shells, true, false).unwrap(),
-            "hello world and moon!"
-        );
-    }
-
-    #[test]
-    fn ut_translate_text_err() {
-        let text = "hello {{ koopa.foo }}!";
-        let shells = ShellMap::new();
-        assert_eq!(
-            Koopa::translate(text, &shells, false, false),
-            Err(Error::KeyUnknown(Key::from_str("koopa.foo").unwrap(), 1, 7))
-        );
-    }
-
-    #[test]
-    fn ut_translate_text_multiline_value() {
-        let text = "hello {{ koopa.multi }} and all!";
-        let mut shells = ShellMap::new();
-        shells.insert(Shell::with(
-            String::from("koopa.multi"),
-            String::from("earth\nvenus\nmars"),
-        ));
-        assert_eq!(
-            Koopa::translate(text, &shells, true, false).unwrap(),
-            "hello earth
-      venus
-      mars and all!"
-        );
-
-        let text = "hello {{ koopa.multi }} and all!";
-        let mut shells = ShellMap::new();
-        shells.insert(Shell::with(
-            String::from("koopa.multi"),
-            String::from("earth\nvenus\nmars\n\n"),
-        ));
-        assert_eq!(
-            Koopa::translate(text, &shells, true, false).unwrap(),
-            "hello earth
-      venus
-      mars
-      
-       and all!"
-        );
+        };
 
-        let text = "hello\n{{ koopa.multi }} and all!";
-        let mut shells = ShellMap::new();
-        shells.insert(Shell::with(
-            String::from("koopa.multi"),
-            String::from("earth\n venus\nmars\n"),
-        ));
-        assert_eq!(
-            Koopa::translate(text, &shells, true, false).unwrap(),
-            "hello
-earth
- venus
-mars
- and all!"
+        // provide information back to the user that the operation was a success
+        help::info(
+            format!(
+                "successfully koopa'ed {} bytes to {:?}",
+                bytes_copied, self.dest
+            ),
+            self.verbose,
         );
+        Ok(())
     }
 }
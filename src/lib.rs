@@ -1,8 +1,23 @@
-pub mod config;
-pub mod error;
-pub mod help;
-pub mod koopa;
-pub mod shell;
+pub(crate) mod builtin;
+pub(crate) mod command;
+pub(crate) mod config;
+pub(crate) mod encoding;
+pub(crate) mod engine;
+pub(crate) mod error;
+pub(crate) mod filter;
+pub(crate) mod help;
+pub(crate) mod koopa;
+pub(crate) mod renderer;
+pub(crate) mod script;
+pub(crate) mod shell;
+pub(crate) mod stream;
+pub(crate) mod template;
 
+pub use engine::{Engine, EngineBuilder};
 pub use error::Error;
+pub use filter::Filters;
+pub use help::Message;
 pub use koopa::Koopa;
+pub use renderer::{Context, Renderer};
+pub use shell::{Key, Shell, ShellMap, Value};
+pub use template::{Delimiters, Diagnostic, Indent};
@@ -0,0 +1,337 @@
+use crate::encoding::Format;
+use crate::error::Error;
+use crate::filter::Filters;
+use crate::help::Message;
+use crate::shell::ShellMap;
+use crate::stream::Stream;
+use crate::template::{Delimiters, Diagnostic, Indent, Partials, Template};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// The name that selects koopa's own templates, which are used for every
+/// source that does not select another renderer.
+pub const KOOPA: &str = "koopa";
+
+/// Everything given to a renderer besides the source itself, as set for the
+/// source being rendered.
+pub struct Context<'a> {
+    /// The path of the source, which problems within it are reported against.
+    pub src: &'a Path,
+    pub shells: &'a ShellMap,
+    pub filters: &'a Filters,
+    pub partials: &'a Partials,
+    pub delimiters: Delimiters,
+    pub indent: Indent,
+    /// The encoding and line endings of the output.
+    pub format: Format,
+    pub force: bool,
+    pub notify: &'a dyn Fn(Message),
+}
+
+/// A template engine that sources can select to render them.
+pub trait Renderer {
+    /// The name sources use to select the renderer.
+    fn name(&self) -> &str;
+
+    /// Renders the text with the shells, filters, and partials of the
+    /// context. Unless `ctx.force` is set, referencing a missing shell should
+    /// fail.
+    fn render(&self, text: &str, ctx: &Context) -> Result<String, String>;
+
+    /// Renders the source read from `reader` into `writer`, returning the
+    /// number of bytes written.
+    ///
+    /// By default, the whole source is decoded and given to
+    /// [Renderer::render] at once, and the output is written in the format
+    /// given by the context.
+    fn render_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        ctx: &Context,
+    ) -> Result<usize, Vec<Diagnostic>> {
+        let failure = |e: String| vec![Diagnostic::from(Error::StreamFailed(Error::lowerize(e)))];
+        let mut bytes = Vec::new();
+        if let Err(e) = reader.read_to_end(&mut bytes) {
+            let error = Error::FileRead(ctx.src.to_path_buf(), Error::lowerize(e.to_string()));
+            return Err(vec![Diagnostic::from(error)]);
+        }
+        let encoding = ctx.format.encoding();
+        if ctx.format.has_bom() == true && bytes.starts_with(encoding.bom()) == true {
+            bytes.drain(..encoding.bom().len());
+        }
+        let text = match encoding.decode(&bytes, true) {
+            Ok((text, _)) => text.replace("\r\n", "\n"),
+            Err(e) => return Err(failure(e)),
+        };
+        let result = match self.render(&text, ctx) {
+            Ok(r) => r,
+            Err(e) => {
+                let error = Error::RenderFailed(self.name().to_string(), e);
+                return Err(vec![Diagnostic::from(error).in_file(ctx.src)]);
+            }
+        };
+        let mut output = match ctx.format.has_bom() {
+            true => encoding.bom().to_vec(),
+            false => Vec::new(),
+        };
+        match ctx.format.encode(&result) {
+            Ok(b) => output.extend(b),
+            Err(e) => return Err(failure(e)),
+        }
+        match writer.write_all(&output).and_then(|_| writer.flush()) {
+            Ok(()) => Ok(output.len()),
+            Err(e) => Err(failure(e.to_string())),
+        }
+    }
+}
+
+/// Renders koopa's own templates, translating the source as it is read.
+pub struct KoopaRenderer;
+
+impl Renderer for KoopaRenderer {
+    fn name(&self) -> &str {
+        KOOPA
+    }
+
+    fn render(&self, text: &str, ctx: &Context) -> Result<String, String> {
+        let mut template =
+            Template::parse_with(text, &ctx.delimiters).map_err(|e| e.to_string())?;
+        template.set_indent(ctx.indent);
+        let mut result = String::new();
+        template
+            .render_into(
+                ctx.shells,
+                ctx.filters,
+                ctx.partials,
+                ctx.force,
+                ctx.notify,
+                &mut result,
+            )
+            .map_err(|mut d| d.remove(0).into_error().to_string())?;
+        Ok(result)
+    }
+
+    fn render_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        ctx: &Context,
+    ) -> Result<usize, Vec<Diagnostic>> {
+        let mut stream = Stream::new(ctx.shells, ctx.filters, ctx.partials, ctx.force, false);
+        stream.set_notify(ctx.notify);
+        stream.set_delimiters(ctx.delimiters.clone());
+        stream.set_indent(ctx.indent);
+        stream.set_format(ctx.format);
+        stream.render(reader, writer).map_err(|diagnostics| {
+            // problems found within the template belong to the source
+            diagnostics
+                .into_iter()
+                .map(|d| match d.location() {
+                    Some(_) => d.in_file(ctx.src),
+                    None => d,
+                })
+                .collect()
+        })
+    }
+}
+
+/// The collection of renderers available to sources.
+#[derive(Clone)]
+pub struct Renderers {
+    inner: HashMap<String, Rc<dyn Renderer>>,
+}
+
+impl Renderers {
+    /// Creates the collection with koopa's own templates and every renderer
+    /// enabled by the crate's features.
+    pub fn new() -> Self {
+        let mut renderers = Self {
+            inner: HashMap::new(),
+        };
+        renderers.register(KoopaRenderer);
+        #[cfg(feature = "jinja")]
+        renderers.register(jinja::Jinja::new());
+        renderers
+    }
+
+    /// Adds the renderer under its name, replacing any existing renderer with
+    /// the same name.
+    pub fn register<R: Renderer + 'static>(&mut self, renderer: R) {
+        self.inner
+            .insert(renderer.name().to_string(), Rc::new(renderer));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Rc<dyn Renderer>> {
+        self.inner.get(name)
+    }
+
+    /// Lists the names of every registered renderer in alphabetical order.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.inner.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        names
+    }
+}
+
+impl std::fmt::Debug for Renderers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Renderers")
+            .field("names", &self.names())
+            .finish()
+    }
+}
+
+#[cfg(feature = "jinja")]
+pub mod jinja {
+    use super::{Context, Renderer};
+    use crate::shell::{self, ShellMap, Value};
+    use minijinja::{Environment, UndefinedBehavior};
+    use std::collections::BTreeMap;
+
+    /// Renders Jinja templates with MiniJinja, where shells are available
+    /// under `koopa` and environment variables under `env` just as they are in
+    /// koopa's own templates.
+    pub struct Jinja {
+        env: Environment<'static>,
+        strict: Environment<'static>,
+    }
+
+    impl Jinja {
+        pub fn new() -> Self {
+            let mut env = Environment::new();
+            env.set_keep_trailing_newline(true);
+            let mut strict = env.clone();
+            strict.set_undefined_behavior(UndefinedBehavior::Strict);
+            Self {
+                env: env,
+                strict: strict,
+            }
+        }
+    }
+
+    impl Renderer for Jinja {
+        fn name(&self) -> &str {
+            "jinja"
+        }
+
+        fn render(&self, text: &str, ctx: &Context) -> Result<String, String> {
+            // namespaces become nested maps, such as `koopa.git.user`
+            let koopa: BTreeMap<String, minijinja::Value> = ctx.shells.nest(
+                &|v| match v {
+                    Value::Str(s) => minijinja::Value::from(s.as_str()),
+                    Value::List(items) => minijinja::Value::from(items.clone()),
+                },
+                &minijinja::Value::from,
+            );
+            let env_shells = ShellMap::from_env();
+            let vars: BTreeMap<&str, String> = env_shells
+                .inner()
+                .iter()
+                .filter_map(|(k, v)| {
+                    Some((k.as_str().strip_prefix(shell::ENV_PREFIX)?, v.to_string()))
+                })
+                .collect();
+            let env = match ctx.force {
+                true => &self.env,
+                false => &self.strict,
+            };
+            env.render_str(text, minijinja::context! { koopa => koopa, env => vars })
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::filter::Filters;
+        use crate::renderer::tests::context;
+        use crate::shell::Shell;
+        use crate::template::Partials;
+        use std::str::FromStr;
+
+        #[test]
+        fn ut_jinja() {
+            let mut shells = ShellMap::new();
+            shells.append(Shell::from_str("name=adder").unwrap());
+            shells.append(Shell::from_str("ports=a").unwrap());
+            shells.append(Shell::from_str("ports=b").unwrap());
+            shells.append(Shell::from_str("git.user=ada").unwrap());
+            let text = "entity {{ koopa.name | upper }} is\n{% for p in koopa.ports %}{{ p }};{% endfor %}\n-- {{ koopa.git.user }}\n";
+            let (filters, partials) = (Filters::new(), Partials::new());
+            let ctx = context(&shells, &filters, &partials, false);
+            assert_eq!(
+                Jinja::new().render(text, &ctx).unwrap(),
+                "entity ADDER is\na;b;\n-- ada\n"
+            );
+            assert!(Jinja::new().render("{{ koopa.missing }}", &ctx).is_err());
+            assert_eq!(
+                Jinja::new().render(
+                    "{{ koopa.missing }}",
+                    &context(&shells, &filters, &partials, true)
+                ),
+                Ok(String::new())
+            );
+            std::env::set_var("KOOPA_UT_JINJA", "ci");
+            assert_eq!(
+                Jinja::new().render("{{ env.KOOPA_UT_JINJA }}", &ctx),
+                Ok(String::from("ci"))
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::encoding::Format;
+    use crate::shell::{Shell, Value};
+    use std::str::FromStr;
+
+    fn ignore(_: Message) {}
+
+    /// Creates the context for rendering a source from the default settings.
+    pub(crate) fn context<'a>(
+        shells: &'a ShellMap,
+        filters: &'a Filters,
+        partials: &'a Partials,
+        force: bool,
+    ) -> Context<'a> {
+        Context {
+            src: Path::new("src.txt"),
+            shells: shells,
+            filters: filters,
+            partials: partials,
+            delimiters: Delimiters::new(),
+            indent: Indent::default(),
+            format: Format::new(),
+            force: force,
+            notify: &ignore,
+        }
+    }
+
+    #[test]
+    fn ut_koopa_renderer() {
+        let renderers = Renderers::new();
+        assert!(renderers.names().contains(&KOOPA) == true);
+        let mut shells = ShellMap::new();
+        shells.insert(Shell::from_str("name=adder").unwrap());
+        let mut filters = Filters::new();
+        filters.register("twice", |v, _| Ok(Value::from(format!("{0}{0}", v))));
+        let partials = Partials::new();
+        let ctx = context(&shells, &filters, &partials, false);
+        let koopa = renderers.get(KOOPA).unwrap();
+        assert_eq!(
+            koopa.render("entity {{ koopa.name | upper }} is", &ctx),
+            Ok(String::from("entity ADDER is"))
+        );
+        // filters registered with the engine are available
+        assert_eq!(
+            koopa.render("{{ koopa.name | twice }}", &ctx),
+            Ok(String::from("adderadder"))
+        );
+        assert!(koopa.render("{{ koopa.missing }}", &ctx).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use crate::error::Error;
+#[cfg(feature = "rhai")]
+use crate::shell::{Key, Value};
+use crate::shell::{Shell, ShellMap};
+#[cfg(feature = "rhai")]
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A script that derives new shells from the existing ones, for values that
+/// need more logic than a placeholder can express.
+///
+/// Scripts are written in [Rhai](https://rhai.rs) and require the `rhai`
+/// feature. The existing shells are available to the script as the constant
+/// map `koopa`, and the script returns a map of new shells. Scripts run in a
+/// sandbox without access to the filesystem, network, or other processes, and
+/// are stopped if they run for too long.
+pub struct Script {
+    path: PathBuf,
+    #[cfg(feature = "rhai")]
+    engine: rhai::Engine,
+    #[cfg(feature = "rhai")]
+    ast: rhai::AST,
+}
+
+impl Script {
+    /// Reads and compiles the script at `path`.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => {
+                return Err(Error::FileRead(
+                    path.to_path_buf(),
+                    Error::lowerize(e.to_string()),
+                ))
+            }
+        };
+        Self::compile(&text, path)
+    }
+
+    /// Compiles the text of a script, where `path` names the script in errors.
+    #[cfg(feature = "rhai")]
+    pub fn compile(text: &str, path: &Path) -> Result<Self, Error> {
+        let engine = Self::sandbox();
+        match engine.compile(text) {
+            Ok(ast) => Ok(Self {
+                path: path.to_path_buf(),
+                engine: engine,
+                ast: ast,
+            }),
+            Err(e) => Err(Error::ScriptInvalid(path.to_path_buf(), e.to_string())),
+        }
+    }
+
+    #[cfg(not(feature = "rhai"))]
+    pub fn compile(_: &str, path: &Path) -> Result<Self, Error> {
+        Err(Error::ScriptUnsupported(path.to_path_buf()))
+    }
+
+    /// Runs the script with the shells, returning the shells it produces.
+    #[cfg(feature = "rhai")]
+    pub fn run(&self, shells: &ShellMap) -> Result<Vec<Shell>, Error> {
+        let failed = |e: String| Error::ScriptFailed(self.path.clone(), e);
+        // namespaces become nested maps, such as `koopa.git.user`
+        let to_map = |tree: BTreeMap<String, rhai::Dynamic>| -> rhai::Map {
+            tree.into_iter().map(|(k, v)| (k.into(), v)).collect()
+        };
+        let koopa = to_map(shells.nest(
+            &|v| match v {
+                Value::Str(s) => rhai::Dynamic::from(s.clone()),
+                Value::List(items) => rhai::Dynamic::from_array(
+                    items.iter().cloned().map(rhai::Dynamic::from).collect(),
+                ),
+            },
+            &|tree| rhai::Dynamic::from_map(to_map(tree)),
+        ));
+        let mut scope = rhai::Scope::new();
+        scope.push_constant("koopa", koopa);
+        let result: rhai::Map = match self.engine.eval_ast_with_scope(&mut scope, &self.ast) {
+            Ok(r) => r,
+            Err(e) => return Err(failed(e.to_string())),
+        };
+
+        let mut derived = Vec::new();
+        Self::collect(result, "", &mut derived).map_err(failed)?;
+        Ok(derived)
+    }
+
+    /// Turns the map returned by a script into shells, where nested maps name
+    /// the namespaces of their shells.
+    #[cfg(feature = "rhai")]
+    fn collect(map: rhai::Map, namespace: &str, derived: &mut Vec<Shell>) -> Result<(), String> {
+        for (name, value) in map {
+            let name = format!("{}{}", namespace, name);
+            let key = match name.parse::<Key>() {
+                Ok(k) => k.into_koopa_key(),
+                Err(e) => return Err(e.to_string()),
+            };
+            // an empty value leaves the shell undefined
+            if value.is_unit() == true {
+                continue;
+            }
+            if value.is_map() == true {
+                Self::collect(value.cast::<rhai::Map>(), &format!("{}.", name), derived)?;
+                continue;
+            }
+            let value = match value.is_array() {
+                true => Value::List(
+                    value
+                        .cast::<rhai::Array>()
+                        .iter()
+                        .map(|i| i.to_string())
+                        .collect(),
+                ),
+                false => Value::Str(value.to_string()),
+            };
+            derived.push(Shell::from((key, value)));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "rhai"))]
+    pub fn run(&self, _: &ShellMap) -> Result<Vec<Shell>, Error> {
+        Err(Error::ScriptUnsupported(self.path.clone()))
+    }
+
+    /// Creates an engine that only offers the language's standard library,
+    /// which has no access to the filesystem, network, or other processes.
+    #[cfg(feature = "rhai")]
+    fn sandbox() -> rhai::Engine {
+        let mut engine = rhai::Engine::new();
+        // prevent loading other scripts and evaluating text as code
+        engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+        engine.disable_symbol("eval");
+        // keep the output of koopa clean
+        engine.on_print(|_| ());
+        engine.on_debug(|_, _, _| ());
+        // stop scripts that run away
+        engine.set_max_operations(1_000_000);
+        engine.set_max_call_levels(64);
+        engine.set_max_expr_depths(64, 32);
+        engine.set_max_string_size(1024 * 1024);
+        engine.set_max_array_size(10_000);
+        engine.set_max_map_size(10_000);
+        engine
+    }
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Script").field("path", &self.path).finish()
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "rhai")]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn run(text: &str, shells: &ShellMap) -> Result<Vec<Shell>, Error> {
+        Script::compile(text, Path::new("shells.rhai"))?.run(shells)
+    }
+
+    #[test]
+    fn ut_script_derives_shells() {
+        let mut shells = ShellMap::new();
+        shells.append(Shell::from_str("width=8").unwrap());
+        shells.append(Shell::from_str("ports=clk").unwrap());
+        shells.append(Shell::from_str("ports=rst").unwrap());
+        shells.append(Shell::from_str("git.user=Ada").unwrap());
+        let text = r#"
+            let width = parse_int(koopa.width);
+            #{
+                msb: width - 1,
+                git: #{ initial: koopa.git.user.sub_string(0, 1) },
+                license: if koopa.ports.len() > 1 { "MIT" } else { "Apache-2.0" },
+                resets: koopa.ports.filter(|p| p.starts_with("rst")),
+                skipped: (),
+            }
+        "#;
+        let mut derived = run(text, &shells).unwrap();
+        derived.sort_by(|a, b| a.key().cmp(b.key()));
+        assert_eq!(
+            derived,
+            vec![
+                Shell::with(String::from("koopa.git.initial"), String::from("A")),
+                Shell::with(String::from("koopa.license"), String::from("MIT")),
+                Shell::with(String::from("koopa.msb"), String::from("7")),
+                Shell::from((
+                    Key::from_str("koopa.resets").unwrap(),
+                    Value::List(vec![String::from("rst")])
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn ut_script_sandbox() {
+        let shells = ShellMap::new();
+        // nothing can be loaded from outside the script
+        assert!(matches!(
+            run("import \"other\" as o; #{}", &shells),
+            Err(Error::ScriptFailed(_, _))
+        ));
+        assert!(matches!(
+            run("eval(\"1\"); #{}", &shells),
+            Err(Error::ScriptInvalid(_, _))
+        ));
+        // scripts that never finish are stopped
+        assert!(matches!(
+            run("loop {} #{}", &shells),
+            Err(Error::ScriptFailed(_, _))
+        ));
+        assert!(matches!(run("42", &shells), Err(Error::ScriptFailed(_, _))));
+        assert!(matches!(
+            run("#{ \"bad key\": 1 }", &shells),
+            Err(Error::ScriptFailed(_, _))
+        ));
+    }
+}
@@ -1,19 +1,37 @@
 use super::Error;
+use crate::filter::Filters;
+use crate::template::{Delimiters, Partials, Template};
 use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::Display;
 use std::hash::Hash;
 use std::{collections::HashMap, str::FromStr};
 
 pub const KEY_PREFIX: &str = "koopa.";
 
+/// The namespace of keys that read environment variables.
+pub const ENV_PREFIX: &str = "env.";
+
 #[derive(Debug, Eq, Clone, PartialOrd, Ord)]
 pub struct Key(String);
 
+impl Default for Key {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Key {
     pub fn new() -> Self {
         Self(String::new())
     }
 
+    /// Creates a key from the raw text without checking if it is valid.
+    pub fn with(s: String) -> Self {
+        Self(s)
+    }
+
     pub fn clear(&mut self) {
         self.0.clear()
     }
@@ -30,23 +48,31 @@ impl Key {
         self.0.trim()
     }
 
+    /// Accesses the full key without any surrounding whitespace.
+    pub fn as_str(&self) -> &str {
+        self.as_internal_repr()
+    }
+
     /// Determines if the given key is indeed a key recognized by koopa.
     pub fn is_koopa_key(&self) -> bool {
         self.as_internal_repr().starts_with(KEY_PREFIX)
     }
 
+    /// Determines if the given key reads an environment variable.
+    pub fn is_env_key(&self) -> bool {
+        self.as_internal_repr().starts_with(ENV_PREFIX)
+    }
+
     /// Accesses the name of the key without the koopa prefix.
     pub fn get_name(&self) -> &str {
-        &self
-            .0
-            .trim()
-            .get(
-                match self.0.trim().find('.') {
-                    Some(i) => i + 1,
-                    None => 0,
-                }..,
-            )
-            .unwrap()
+        let key = self.as_internal_repr();
+        key.strip_prefix(KEY_PREFIX).unwrap_or(key)
+    }
+
+    /// Splits the name of the key into the namespaces that contain it,
+    /// followed by its own name.
+    pub fn get_parts(&self) -> Vec<&str> {
+        self.get_name().split('.').collect()
     }
 
     /// Transforms the given key into a koopa key, if not already.
@@ -68,18 +94,16 @@ impl FromStr for Key {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let word_count = s.split_whitespace().into_iter().count();
+        let word_count = s.split_whitespace().count();
         if word_count > 1 {
             return Err(Error::KeyContainsWhitespace(s.to_string()));
         }
         if s.contains('\n') == true {
             return Err(Error::KeyContainsNewline(s.to_string()));
         }
-        let dot_count = s.chars().filter(|c| c == &'.').count();
-        if s.trim().starts_with(KEY_PREFIX) == true && dot_count > 1 {
-            return Err(Error::KeyContainsMoreDots(s.to_string()));
-        } else if s.trim().starts_with(KEY_PREFIX) == false && dot_count > 0 {
-            return Err(Error::KeyContainsOneDot(s.to_string()));
+        // namespaces are separated by dots, such as "koopa.git.user"
+        if s.trim().split('.').any(|part| part.is_empty()) == true {
+            return Err(Error::KeyContainsEmptyName(s.to_string()));
         }
         Ok(Self(s.to_string()))
     }
@@ -88,7 +112,6 @@ impl FromStr for Key {
 impl Hash for Key {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         state.write(self.as_internal_repr().as_bytes());
-        state.finish();
     }
 }
 
@@ -105,29 +128,67 @@ impl PartialEq for Key {
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize)]
-pub struct Value(String);
+#[serde(untagged)]
+pub enum Value {
+    Str(String),
+    List(Vec<String>),
+}
 
 impl Value {
-    pub fn as_str(&self) -> &str {
-        &self.0
+    /// Accesses the value as a single text, where list items are placed on
+    /// separate lines.
+    pub fn to_text(&self) -> Cow<'_, str> {
+        match self {
+            Self::Str(s) => Cow::Borrowed(s),
+            Self::List(items) => Cow::Owned(items.join("\n")),
+        }
+    }
+
+    /// Returns the items to iterate over. A single value is treated as a list
+    /// of one item.
+    pub fn items(&self) -> Vec<&str> {
+        match self {
+            Self::Str(s) => vec![s.as_str()],
+            Self::List(items) => items.iter().map(|s| s.as_str()).collect(),
+        }
+    }
+
+    /// Adds `other` to the end of this value, turning it into a list if it is
+    /// not already one.
+    pub fn extend(&mut self, other: Value) {
+        let mut items: Vec<String> = match std::mem::replace(self, Self::List(Vec::new())) {
+            Self::Str(s) => vec![s],
+            Self::List(items) => items,
+        };
+        match other {
+            Self::Str(s) => items.push(s),
+            Self::List(other) => items.extend(other),
+        }
+        *self = Self::List(items);
     }
 }
 
 impl From<&str> for Value {
     fn from(value: &str) -> Self {
-        Self(value.to_string())
+        Self::Str(value.to_string())
     }
 }
 
 impl From<String> for Value {
     fn from(value: String) -> Self {
-        Self(value)
+        Self::Str(value)
+    }
+}
+
+impl From<Vec<String>> for Value {
+    fn from(value: Vec<String>) -> Self {
+        Self::List(value)
     }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.to_text())
     }
 }
 
@@ -141,7 +202,7 @@ impl Shell {
     pub fn with(key: String, value: String) -> Self {
         Self {
             key: Key(key),
-            value: Value(value),
+            value: Value::Str(value),
         }
     }
 
@@ -182,11 +243,17 @@ impl FromStr for Shell {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct ShellMap {
     inner: HashMap<Key, Value>,
 }
 
+impl Default for ShellMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ShellMap {
     pub fn new() -> Self {
         Self {
@@ -194,6 +261,19 @@ impl ShellMap {
         }
     }
 
+    /// Creates a map of the environment variables, each under the "env."
+    /// namespace. Variables that are not valid unicode are left out.
+    pub fn from_env() -> Self {
+        Self {
+            inner: std::env::vars_os()
+                .filter_map(|(k, v)| match (k.into_string(), v.into_string()) {
+                    (Ok(k), Ok(v)) => Some((Key(format!("{}{}", ENV_PREFIX, k)), Value::Str(v))),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
     pub fn insert(&mut self, shell: Shell) -> Option<Value> {
         let (key, value) = shell.split();
         self.inner.insert(key, value)
@@ -203,6 +283,22 @@ impl ShellMap {
         self.inner.get(key)
     }
 
+    pub fn remove(&mut self, key: &Key) -> Option<Value> {
+        self.inner.remove(key)
+    }
+
+    /// Inserts the shell, collecting its value into a list alongside the
+    /// existing value if the key is already present.
+    pub fn append(&mut self, shell: Shell) {
+        let (key, value) = shell.split();
+        match self.inner.get_mut(&key) {
+            Some(existing) => existing.extend(value),
+            None => {
+                self.inner.insert(key, value);
+            }
+        }
+    }
+
     /// Inserts existing shell entries into the current map, overwriting entries
     /// if they already existed.
     pub fn merge(&mut self, shells: ShellMap) {
@@ -217,12 +313,215 @@ impl ShellMap {
     pub fn inner(&self) -> &HashMap<Key, Value> {
         &self.inner
     }
+
+    /// Arranges the koopa shells into a tree by the namespaces in their names,
+    /// such that "koopa.git.user" is found at "user" within "git".
+    ///
+    /// Every value is converted with `leaf` and every namespace with `branch`.
+    /// A name that is both a shell and a namespace keeps the shell.
+    pub fn nest<T>(
+        &self,
+        leaf: &dyn Fn(&Value) -> T,
+        branch: &dyn Fn(BTreeMap<String, T>) -> T,
+    ) -> BTreeMap<String, T> {
+        let mut entries: Vec<(Vec<&str>, &Value)> = self
+            .inner
+            .iter()
+            .filter(|(k, _)| k.is_koopa_key() == true)
+            .map(|(k, v)| (k.get_parts(), v))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Self::nest_parts(&entries, 0, leaf, branch)
+    }
+
+    fn nest_parts<T>(
+        entries: &[(Vec<&str>, &Value)],
+        depth: usize,
+        leaf: &dyn Fn(&Value) -> T,
+        branch: &dyn Fn(BTreeMap<String, T>) -> T,
+    ) -> BTreeMap<String, T> {
+        let mut tree = BTreeMap::new();
+        let mut rest = entries;
+        while let Some((parts, _)) = rest.first() {
+            let name = parts[depth];
+            // entries are sorted, so a namespace's entries are next to each other
+            let count = rest.iter().take_while(|(p, _)| p[depth] == name).count();
+            let (group, next) = rest.split_at(count);
+            let node = match group.iter().find(|(p, _)| p.len() == depth + 1) {
+                Some((_, value)) => leaf(value),
+                None => branch(Self::nest_parts(group, depth + 1, leaf, branch)),
+            };
+            tree.insert(name.to_string(), node);
+            rest = next;
+        }
+        tree
+    }
+
+    /// Evaluates every shell whose value references koopa or environment keys
+    /// within tags written with the given delimiters, so shells can be built
+    /// from other shells. Any other value is kept as-is. A shell is evaluated
+    /// after the shells it references, and shells that reference each other in
+    /// a cycle are an error. Unless `force` is set, referencing a missing shell
+    /// is an error.
+    pub fn resolve(
+        &mut self,
+        filters: &Filters,
+        delimiters: &Delimiters,
+        force: bool,
+    ) -> Result<(), Error> {
+        let computed = self.computed(delimiters)?;
+        self.evaluate(&computed, filters, force)
+    }
+
+    /// Collects the shells whose values reference koopa or environment keys
+    /// within tags written with the given delimiters.
+    pub fn computed(&self, delimiters: &Delimiters) -> Result<Computed, Error> {
+        let mut computed: HashMap<Key, Vec<Template>> = HashMap::new();
+        for (key, value) in &self.inner {
+            let items = value.items();
+            let mentions = |keys: &[&str]| {
+                items
+                    .iter()
+                    .any(|s| keys.iter().any(|k| s.contains(k) == true) == true)
+            };
+            if mentions(&delimiters.openers()) == false {
+                continue;
+            }
+            let templates: Vec<Template> = match items
+                .iter()
+                .map(|s| Template::parse_with(s, delimiters))
+                .collect()
+            {
+                Ok(t) => t,
+                // text that cannot be a template is only a mistake if it was
+                // meant to reference other shells
+                Err(e) => match mentions(&[KEY_PREFIX, ENV_PREFIX]) {
+                    true => {
+                        return Err(Error::ShellInvalid(key.as_str().to_string(), e.to_string()))
+                    }
+                    false => continue,
+                },
+            };
+            if templates.iter().any(|t| t.references_shells() == true) == true {
+                computed.insert(key.clone(), templates);
+            }
+        }
+        Ok(Computed { inner: computed })
+    }
+
+    /// Evaluates the computed shells, where any shell they reference that is
+    /// not among them is taken as it is.
+    pub fn evaluate(
+        &mut self,
+        computed: &Computed,
+        filters: &Filters,
+        force: bool,
+    ) -> Result<(), Error> {
+        // evaluate in a fixed order so any cycle is always reported the same way
+        let mut order: Vec<&Key> = computed.inner.keys().collect();
+        order.sort();
+        let mut done = HashSet::new();
+        for key in order {
+            self.evaluate_key(
+                key,
+                &computed.inner,
+                &mut Vec::new(),
+                &mut done,
+                filters,
+                force,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Evaluates the computed shell `key` after the computed shells it
+    /// references, where `chain` holds the shells waiting on it.
+    fn evaluate_key<'a>(
+        &mut self,
+        key: &'a Key,
+        computed: &'a HashMap<Key, Vec<Template>>,
+        chain: &mut Vec<&'a Key>,
+        done: &mut HashSet<&'a Key>,
+        filters: &Filters,
+        force: bool,
+    ) -> Result<(), Error> {
+        if done.contains(key) == true {
+            return Ok(());
+        }
+        if let Some(i) = chain.iter().position(|k| *k == key) {
+            let cycle: Vec<&str> = chain[i..]
+                .iter()
+                .chain(std::iter::once(&key))
+                .map(|k| k.as_str())
+                .collect();
+            return Err(Error::ShellCycle(cycle.join(" -> ")));
+        }
+        let templates = computed.get(key).unwrap();
+        chain.push(key);
+        for dep in templates.iter().flat_map(|t| t.keys()) {
+            if let Some((dep, _)) = computed.get_key_value(dep) {
+                self.evaluate_key(dep, computed, chain, done, filters, force)?;
+            }
+        }
+        chain.pop();
+
+        let mut items = Vec::new();
+        for t in templates {
+            match t.render(self, filters, &Partials::new(), force, false) {
+                Ok(text) => items.push(text),
+                Err(e) => return Err(Error::ShellInvalid(key.as_str().to_string(), e.to_string())),
+            }
+        }
+        let value = match self.inner.get(key) {
+            Some(Value::List(_)) => Value::List(items),
+            _ => Value::Str(items.remove(0)),
+        };
+        self.inner.insert(key.clone(), value);
+        done.insert(key);
+        Ok(())
+    }
+}
+
+/// The shells whose values are built from other shells, kept as templates so
+/// they can be evaluated against different sets of shells.
+#[derive(Debug)]
+pub struct Computed {
+    inner: HashMap<Key, Vec<Template>>,
+}
+
+impl Computed {
+    /// Removes and returns the shells that reference any of the `keys`, either
+    /// directly or through other computed shells.
+    pub fn split_off(&mut self, keys: &[Key]) -> Self {
+        let mut moved: HashMap<Key, Vec<Template>> = HashMap::new();
+        loop {
+            let found: Vec<Key> = self
+                .inner
+                .iter()
+                .filter(|(_, templates)| {
+                    templates
+                        .iter()
+                        .flat_map(|t| t.keys())
+                        .any(|k| keys.contains(k) == true || moved.contains_key(k) == true)
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            if found.is_empty() == true {
+                break;
+            }
+            for key in found {
+                let templates = self.inner.remove(&key).unwrap();
+                moved.insert(key, templates);
+            }
+        }
+        Self { inner: moved }
+    }
 }
 
 impl From<&Vec<Shell>> for ShellMap {
     fn from(value: &Vec<Shell>) -> Self {
         let mut shell_map = ShellMap::new();
-        value.into_iter().for_each(|shell| {
+        value.iter().for_each(|shell| {
             shell_map.insert(shell.clone());
         });
         shell_map
@@ -257,7 +556,7 @@ impl<'de> Deserialize<'de> for Key {
             }
         }
 
-        deserializer.deserialize_map(LayerVisitor)
+        deserializer.deserialize_str(LayerVisitor)
     }
 }
 
@@ -274,6 +573,12 @@ mod tests {
         assert_eq!(Key::from_str(s), Ok(Key(s.to_string())));
         let s = "koopa.name";
         assert_eq!(Key::from_str(s), Ok(Key(s.to_string())));
+        let s = "git.user";
+        assert_eq!(Key::from_str(s), Ok(Key(s.to_string())));
+        let s = "koopa.nested.key";
+        assert_eq!(Key::from_str(s), Ok(Key(s.to_string())));
+        assert_eq!(Key::from_str(s).unwrap().get_name(), "nested.key");
+        assert_eq!(Key::from_str(s).unwrap().get_parts(), vec!["nested", "key"]);
     }
 
     #[test]
@@ -288,10 +593,165 @@ mod tests {
             Key::from_str(s),
             Err(Error::KeyContainsNewline(s.to_string()))
         );
-        let s = "koopa.nested.key";
+        for s in ["koopa..key", "koopa.", ".key", "git."] {
+            assert_eq!(
+                Key::from_str(s),
+                Err(Error::KeyContainsEmptyName(s.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn ut_nest_shells() {
+        let mut shells = ShellMap::new();
+        shells.append(Shell::from_str("name=adder").unwrap());
+        shells.append(Shell::from_str("git.user=ada").unwrap());
+        shells.append(Shell::from_str("git.remote.url=u").unwrap());
+        let branch = |tree: BTreeMap<String, String>| {
+            let items: Vec<String> = tree.iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+            format!("({})", items.join(","))
+        };
+        let tree = shells.nest(&|v| v.to_string(), &branch);
+        assert_eq!(branch(tree), "(git:(remote:(url:u),user:ada),name:adder)");
+    }
+
+    #[test]
+    fn ut_append_repeated_shells() {
+        let mut shells = ShellMap::new();
+        shells.append(Shell::from_str("ports=clk").unwrap());
         assert_eq!(
-            Key::from_str(s),
-            Err(Error::KeyContainsMoreDots(s.to_string()))
+            shells.get(&Key::from_str("koopa.ports").unwrap()),
+            Some(&Value::from("clk"))
+        );
+        shells.append(Shell::from_str("ports=rst").unwrap());
+        shells.append(Shell::from_str("ports=data").unwrap());
+        assert_eq!(
+            shells.get(&Key::from_str("koopa.ports").unwrap()),
+            Some(&Value::from(vec![
+                String::from("clk"),
+                String::from("rst"),
+                String::from("data")
+            ]))
+        );
+    }
+
+    #[test]
+    fn ut_resolve_computed_shells() {
+        let mut shells = ShellMap::new();
+        for s in [
+            "header=Copyright {{ koopa.year }} {{ koopa.user }}",
+            "crate={{ koopa.project | snake }}-core",
+            "project=MyProject",
+            "year={{ koopa.copyright ? \"2024\" }}",
+            "user=kp",
+            "deps={{ koopa.crate }}",
+            "deps=serde",
+        ] {
+            shells.append(Shell::from_str(s).unwrap());
+        }
+        shells
+            .resolve(&Filters::new(), &Delimiters::new(), false)
+            .unwrap();
+        let get = |k: &str| shells.get(&Key::from_str(k).unwrap()).cloned();
+        assert_eq!(get("koopa.crate"), Some(Value::from("my_project-core")));
+        assert_eq!(get("koopa.header"), Some(Value::from("Copyright 2024 kp")));
+        assert_eq!(
+            get("koopa.deps"),
+            Some(Value::List(vec![
+                String::from("my_project-core"),
+                String::from("serde")
+            ]))
+        );
+    }
+
+    #[test]
+    fn ut_resolve_literal_shells() {
+        let mut shells = ShellMap::new();
+        for s in [
+            "snippet=a {{# b",
+            "section={{#each items}}{{ name }}{{/each}}",
+            "jinja={% if user %}hi{% endif %}",
+            "name=<< koopa.project >>",
+            "project=kp",
+        ] {
+            shells.insert(Shell::from_str(s).unwrap());
+        }
+        let before = shells.clone();
+        shells
+            .resolve(&Filters::new(), &Delimiters::new(), false)
+            .unwrap();
+        assert_eq!(shells, before);
+
+        // only the configured delimiters open tags
+        let mut delimiters = Delimiters::new();
+        delimiters.set_variable("<<", ">>");
+        shells.resolve(&Filters::new(), &delimiters, false).unwrap();
+        assert_eq!(
+            shells.get(&Key::from_str("koopa.name").unwrap()),
+            Some(&Value::from("kp"))
+        );
+    }
+
+    #[test]
+    fn ut_split_computed_shells() {
+        let mut shells = ShellMap::new();
+        for s in [
+            "guard={{ koopa.module | upper }}_H",
+            "module={{ koopa.project }}_{{ koopa.name }}",
+            "crate={{ koopa.project }}-core",
+            "project=kp",
+        ] {
+            shells.insert(Shell::from_str(s).unwrap());
+        }
+        let mut computed = shells.computed(&Delimiters::new()).unwrap();
+        let per_file = computed.split_off(&[Key::from_str("koopa.name").unwrap()]);
+        shells.evaluate(&computed, &Filters::new(), false).unwrap();
+        let get = |shells: &ShellMap, k: &str| shells.get(&Key::from_str(k).unwrap()).cloned();
+        assert_eq!(get(&shells, "koopa.crate"), Some(Value::from("kp-core")));
+        assert_eq!(
+            get(&shells, "koopa.guard"),
+            Some(Value::from("{{ koopa.module | upper }}_H"))
+        );
+
+        for name in ["fifo", "adder"] {
+            let mut file = shells.clone();
+            file.insert(Shell::with(String::from("koopa.name"), name.to_string()));
+            file.evaluate(&per_file, &Filters::new(), false).unwrap();
+            let guard = format!("KP_{}_H", name.to_uppercase());
+            assert_eq!(get(&file, "koopa.guard"), Some(Value::from(guard.as_str())));
+        }
+    }
+
+    #[test]
+    fn ut_resolve_errors() {
+        let mut shells = ShellMap::new();
+        for s in [
+            "a={{ koopa.b }}",
+            "b=x{{ koopa.c }}",
+            "c={{ koopa.a }}",
+            "d=1",
+        ] {
+            shells.insert(Shell::from_str(s).unwrap());
+        }
+        assert_eq!(
+            shells.resolve(&Filters::new(), &Delimiters::new(), false),
+            Err(Error::ShellCycle(String::from(
+                "koopa.a -> koopa.b -> koopa.c -> koopa.a"
+            )))
+        );
+
+        let mut shells = ShellMap::new();
+        shells.insert(Shell::from_str("a={{ koopa.missing }}").unwrap());
+        assert!(matches!(
+            shells.clone().resolve(&Filters::new(), &Delimiters::new(), false),
+            Err(Error::ShellInvalid(k, _)) if k == "koopa.a"
+        ));
+        shells
+            .resolve(&Filters::new(), &Delimiters::new(), true)
+            .unwrap();
+        assert_eq!(
+            shells.get(&Key::from_str("koopa.a").unwrap()),
+            Some(&Value::from("{{ koopa.missing }}"))
         );
     }
 }
@@ -0,0 +1,465 @@
+use crate::encoding::{Encoding, Format};
+use crate::error::Error;
+use crate::filter::Filters;
+use crate::help::Message;
+use crate::shell::ShellMap;
+use crate::template::{self, Delimiters, Diagnostic, Indent, Partials, Template};
+use std::io::{ErrorKind, Read, Write};
+
+/// The default number of bytes read from the source at a time.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of bytes checked at the start of a source when deciding if it is
+/// binary.
+pub const SNIFF_SIZE: usize = 8 * 1024;
+
+/// Guesses if the bytes at the start of a source belong to a binary file.
+///
+/// Bytes that begin with a byte order mark are text. Otherwise, bytes are
+/// binary when they contain a NUL byte, or when they are not valid UTF-8 and
+/// contain control characters that are unlikely to appear in Latin-1 text. A
+/// character cut off at the end of the bytes is not counted as invalid.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    let bytes = &bytes[..bytes.len().min(SNIFF_SIZE)];
+    if Encoding::from_bom(bytes).is_some() == true {
+        return false;
+    }
+    if bytes.contains(&0) == true {
+        return true;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(_) => false,
+        Err(e) if e.error_len().is_none() => false,
+        Err(_) => bytes
+            .iter()
+            .any(|b| (*b < 0x20 && b"\t\n\r\x0c\x1b".contains(b) == false) || *b == 0x7f),
+    }
+}
+
+/// Renders a source as it is read and writes the output as it goes, so large
+/// sources are never held in memory all at once.
+///
+/// The source is rendered in pieces, where each piece ends on a line that
+/// closes every tag and block opened within it. A block is kept in memory until
+/// it is closed, so memory is bounded by the largest block rather than by the
+/// size of the source. A stray delimiter that koopa would not interpret only
+/// holds back the few kilobytes of text that follow it.
+pub struct Stream<'a> {
+    shells: &'a ShellMap,
+    filters: &'a Filters,
+    partials: &'a Partials,
+    delimiters: Delimiters,
+    indent: Indent,
+    force: bool,
+    verbose: bool,
+    notify: Option<&'a dyn Fn(Message)>,
+    chunk_size: usize,
+    format: Format,
+}
+
+impl<'a> Stream<'a> {
+    pub fn new(
+        shells: &'a ShellMap,
+        filters: &'a Filters,
+        partials: &'a Partials,
+        force: bool,
+        verbose: bool,
+    ) -> Self {
+        Self {
+            shells: shells,
+            filters: filters,
+            partials: partials,
+            delimiters: Delimiters::new(),
+            indent: Indent::default(),
+            force: force,
+            verbose: verbose,
+            notify: None,
+            chunk_size: CHUNK_SIZE,
+            format: Format::new(),
+        }
+    }
+
+    pub fn set_delimiters(&mut self, delimiters: Delimiters) {
+        self.delimiters = delimiters;
+    }
+
+    pub fn set_indent(&mut self, indent: Indent) {
+        self.indent = indent;
+    }
+
+    /// Reads the source in chunks of `chunk_size` bytes, so tests can split tags
+    /// across every chunk boundary.
+    #[cfg(test)]
+    fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+
+    /// Gives messages about the progress of rendering to `notify` instead of
+    /// showing them when verbose.
+    pub fn set_notify(&mut self, notify: &'a dyn Fn(Message)) {
+        self.notify = Some(notify);
+    }
+
+    /// Sets how the source is stored. The source is rendered with `'\n'` line
+    /// endings, and the output is written back in the same format.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Renders everything read from `reader` into `writer`, returning the
+    /// number of bytes written. Every problem found across the source is
+    /// returned together.
+    pub fn render<R: Read, W: Write>(
+        &self,
+        mut reader: R,
+        mut writer: W,
+    ) -> Result<usize, Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        let encoding = self.format.encoding();
+        // bytes read that do not yet form a complete character
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut bom_left = self.format.has_bom();
+        // a carriage return at the end of the decoded text that may be followed
+        // by a line feed
+        let mut cr = false;
+        // text read that has not been rendered, beginning at line `line`
+        let mut pending = String::new();
+        let mut line = 1;
+        // output of the last unfinished line, which later pieces continue
+        let mut tail = String::new();
+        let mut written = 0;
+        if self.format.has_bom() == true {
+            written += self.write(&mut writer, encoding.bom())?;
+        }
+        // avoid searching the same unclosed block again until it has grown
+        let mut retry_at = 0;
+
+        let mut chunk = vec![0u8; self.chunk_size];
+        loop {
+            let n = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Self::failure(e.to_string())),
+            };
+            bytes.extend_from_slice(&chunk[..n]);
+            if bom_left == true {
+                if bytes.len() < encoding.bom().len() {
+                    continue;
+                }
+                if bytes.starts_with(encoding.bom()) == true {
+                    bytes.drain(..encoding.bom().len());
+                }
+                bom_left = false;
+            }
+            // keep any character split across the end of the chunk for later
+            let (text, used) = match encoding.decode(&bytes, false) {
+                Ok(r) => r,
+                Err(e) => return Err(Self::failure(e)),
+            };
+            bytes.drain(..used);
+            pending.push_str(&Self::normalize(text, &mut cr, false));
+
+            if pending.len() < retry_at {
+                continue;
+            }
+            match self.split(&pending, line) {
+                Some((at, piece)) => {
+                    written +=
+                        self.write_piece(&piece, &mut tail, &mut writer, &mut diagnostics, false)?;
+                    line += pending[..at].matches('\n').count();
+                    pending.drain(..at);
+                    retry_at = 0;
+                }
+                None => retry_at = pending.len() * 2,
+            }
+        }
+        match encoding.decode(&bytes, true) {
+            Ok((text, _)) => pending.push_str(&Self::normalize(text, &mut cr, true)),
+            Err(e) => return Err(Self::failure(e)),
+        }
+        // the rest of the source reports anything left unclosed
+        match self.parse(&pending, line, true) {
+            Ok(piece) => {
+                written +=
+                    self.write_piece(&piece, &mut tail, &mut writer, &mut diagnostics, true)?
+            }
+            Err(e) => diagnostics.push(Diagnostic::from(e).quote(&pending, line)),
+        }
+        if let Err(e) = writer.flush() {
+            return Err(Self::failure(e.to_string()));
+        }
+        match diagnostics.is_empty() {
+            true => Ok(written),
+            false => Err(diagnostics),
+        }
+    }
+
+    /// Finds the latest point in the pending text where the text before it can
+    /// be rendered on its own, returning the point and the parsed text.
+    fn split(&self, pending: &str, line: usize) -> Option<(usize, Template)> {
+        let at = pending
+            .rmatch_indices('\n')
+            .map(|(i, _)| i + 1)
+            .find(|at| template::is_boundary(pending, *at, &self.delimiters) == true)?;
+        // an error here is either an unfinished tag or block, or a real error
+        // that is reported once the whole source is read
+        match self.parse(&pending[..at], line, false) {
+            Ok(piece) => Some((at, piece)),
+            Err(_) => None,
+        }
+    }
+
+    fn parse(&self, text: &str, line: usize, complete: bool) -> Result<Template, Error> {
+        let mut piece = Template::parse_from(text, &self.delimiters, line, complete)?;
+        piece.set_indent(self.indent);
+        Ok(piece)
+    }
+
+    /// Renders the piece after the output of the last unfinished line and
+    /// writes every finished line, returning the number of bytes written.
+    fn write_piece<W: Write>(
+        &self,
+        piece: &Template,
+        tail: &mut String,
+        writer: &mut W,
+        diagnostics: &mut Vec<Diagnostic>,
+        last: bool,
+    ) -> Result<usize, Vec<Diagnostic>> {
+        let mut result = std::mem::take(tail);
+        let show = |m: Message| m.show(self.verbose);
+        if let Err(mut d) = piece.render_into(
+            self.shells,
+            self.filters,
+            self.partials,
+            self.force,
+            self.notify.unwrap_or(&show),
+            &mut result,
+        ) {
+            diagnostics.append(&mut d);
+        }
+        let end = match last {
+            true => result.len(),
+            false => result.rfind('\n').map(|i| i + 1).unwrap_or(0),
+        };
+        let bytes = match self.format.encode(&result[..end]) {
+            Ok(b) => b,
+            Err(e) => return Err(Self::failure(e)),
+        };
+        *tail = result.split_off(end);
+        self.write(writer, &bytes)
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, bytes: &[u8]) -> Result<usize, Vec<Diagnostic>> {
+        match writer.write_all(bytes) {
+            Ok(()) => Ok(bytes.len()),
+            Err(e) => Err(Self::failure(e.to_string())),
+        }
+    }
+
+    /// Converts every `"\r\n"` in the text into `'\n'`. Unless `last` is set, a
+    /// carriage return at the end of the text is held back in `cr` until the
+    /// next text is known.
+    fn normalize(text: String, cr: &mut bool, last: bool) -> String {
+        let mut text = match *cr {
+            true => format!("\r{}", text),
+            false => text,
+        };
+        *cr = last == false && text.ends_with('\r');
+        if *cr == true {
+            text.pop();
+        }
+        text.replace("\r\n", "\n")
+    }
+
+    fn failure(e: String) -> Vec<Diagnostic> {
+        vec![Diagnostic::from(Error::StreamFailed(Error::lowerize(e)))]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::Shell;
+    use std::cell::RefCell;
+
+    fn shells() -> ShellMap {
+        let mut shells = ShellMap::new();
+        shells.insert(Shell::with(
+            String::from("koopa.name"),
+            String::from("fifo"),
+        ));
+        shells.insert(Shell::with(
+            String::from("koopa.license"),
+            String::from("MIT License\n\nCopyright (c) koopa"),
+        ));
+        shells
+    }
+
+    fn stream(text: &str, chunk_size: usize, indent: Indent) -> Result<String, Vec<Diagnostic>> {
+        let (shells, filters, partials) = (shells(), Filters::new(), Partials::new());
+        let mut stream = Stream::new(&shells, &filters, &partials, false, false);
+        stream.set_chunk_size(chunk_size);
+        stream.set_indent(indent);
+        let mut output = Vec::new();
+        let written = stream.render(text.as_bytes(), &mut output)?;
+        assert_eq!(written, output.len());
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn ut_stream_matches_render() {
+        let text = "\
+// {{ koopa.license }}
+entity {{ koopa.name | upper }} is é
+{% if koopa.name == \"fifo\" %}
+    {{ koopa.license }}
+{% endif %}
+{{# note #}}
+a  {{- koopa.name -}}
+   b
+  {{- koopa.name }}
+{% raw %}{{ x }}
+{% endraw %}end
+printf(\"{%s\", x); {{ y
+}}
+";
+        for indent in [Indent::Spaces, Indent::Prefix] {
+            let mut template = Template::parse(text).unwrap();
+            template.set_indent(indent);
+            let expected = template
+                .render(&shells(), &Filters::new(), &Partials::new(), false, false)
+                .unwrap();
+            // tags and characters are split across every possible chunk boundary
+            for chunk_size in 1..=text.len() + 1 {
+                assert_eq!(stream(text, chunk_size, indent).unwrap(), expected);
+            }
+        }
+    }
+
+    /// A source that records how much output was written by the time it is
+    /// read to the end.
+    struct Probe<'a> {
+        text: &'a [u8],
+        output: &'a RefCell<Vec<u8>>,
+        written_at_end: Option<usize>,
+    }
+
+    impl Read for Probe<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.text.read(buf)?;
+            if n == 0 && self.written_at_end.is_none() {
+                self.written_at_end = Some(self.output.borrow().len());
+            }
+            Ok(n)
+        }
+    }
+
+    struct Shared<'a>(&'a RefCell<Vec<u8>>);
+
+    impl Write for Shared<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ut_stream_stray_delimiter() {
+        let (shells, filters, partials) = (shells(), Filters::new(), Partials::new());
+        for stray in ["printf(\"%d {%s\", x);\n", "a {{ b\n"] {
+            let text = format!("{}{}", stray, "{{ koopa.name }}\n".repeat(2000));
+            let expected = Template::parse(&text)
+                .unwrap()
+                .render(&shells, &filters, &partials, false, false)
+                .unwrap();
+            let output = RefCell::new(Vec::new());
+            let mut probe = Probe {
+                text: text.as_bytes(),
+                output: &output,
+                written_at_end: None,
+            };
+            let mut stream = Stream::new(&shells, &filters, &partials, false, false);
+            stream.set_chunk_size(256);
+            stream.render(&mut probe, Shared(&output)).unwrap();
+            assert_eq!(String::from_utf8(output.take()).unwrap(), expected);
+            // the text after the stray delimiter is not held until the end
+            assert!(probe.written_at_end.unwrap() > expected.len() / 2);
+        }
+    }
+
+    #[test]
+    fn ut_stream_round_trip() {
+        let (shells, filters, partials) = (shells(), Filters::new(), Partials::new());
+        let source: Vec<u8> = [0xff, 0xfe]
+            .into_iter()
+            .chain(
+                "@echo off\r\nrem {{ koopa.license }}\r\necho {{ koopa.name }}\r\n"
+                    .encode_utf16()
+                    .flat_map(|u| u.to_le_bytes()),
+            )
+            .collect();
+        let expected: Vec<u8> = [0xff, 0xfe]
+            .into_iter()
+            .chain(
+                "@echo off\r\nrem MIT License\r\n    \r\n    Copyright (c) koopa\r\necho fifo\r\n"
+                    .encode_utf16()
+                    .flat_map(|u| u.to_le_bytes()),
+            )
+            .collect();
+        for chunk_size in [1, 2, 3, 7, 1024] {
+            let mut stream = Stream::new(&shells, &filters, &partials, false, false);
+            stream.set_chunk_size(chunk_size);
+            stream.set_format(Format::detect(&source, None));
+            let mut output = Vec::new();
+            stream.render(source.as_slice(), &mut output).unwrap();
+            assert_eq!(output, expected);
+        }
+
+        // latin-1 text stays latin-1
+        let source = b"caf\xe9 {{ koopa.name }}\n";
+        let mut stream = Stream::new(&shells, &filters, &partials, false, false);
+        stream.set_format(Format::detect(source, None));
+        let mut output = Vec::new();
+        stream.render(source.as_slice(), &mut output).unwrap();
+        assert_eq!(output, b"caf\xe9 fifo\n");
+    }
+
+    #[test]
+    fn ut_is_binary() {
+        assert!(is_binary(b"module top;\n") == false);
+        assert!(is_binary("entité".as_bytes()) == false);
+        // a character split at the end of the checked bytes is still text
+        assert!(is_binary(&"é".as_bytes()[..1]) == false);
+        assert!(is_binary(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR") == true);
+        assert!(is_binary(b"%PDF-1.7\n\xe2\xe3\xcf\xd3\n\x01\x13stream") == true);
+        // text in other encodings
+        assert!(is_binary(b"caf\xe9 cr\xe8me\r\n") == false);
+        assert!(is_binary(b"\xff\xfea\0b\0") == false);
+        assert!(is_binary(b"") == false);
+    }
+
+    #[test]
+    fn ut_stream_errors() {
+        let text = "a\nb {{ koopa.nme }}\n{% if koopa.name %}\nc\n{{ koopa.x }}\n";
+        for chunk_size in [1, 3, 64] {
+            let errors: Vec<Error> = stream(text, chunk_size, Indent::Spaces)
+                .unwrap_err()
+                .into_iter()
+                .map(|d| d.into_error())
+                .collect();
+            assert!(errors.contains(&Error::BlockUnclosed(String::from("if"), 3, 1)));
+        }
+        let errors: Vec<Error> = stream("a\n\nb {{ koopa.nme }}\nc\n{{ koopa.x", 2, Indent::Spaces)
+            .unwrap_err()
+            .into_iter()
+            .map(|d| d.into_error())
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1], Error::TagUnclosed(String::from("}}"), 5, 1));
+        assert!(matches!(errors[0], Error::KeyUnknown(_, 3, 3)));
+    }
+}
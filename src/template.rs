@@ -0,0 +1,2312 @@
+use crate::error::Error;
+use crate::filter::{self, FilterCall, Filters};
+use crate::help::Message;
+use crate::shell::{Key, ShellMap, Value};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const COMMENT_MARKER: &str = "#";
+const TRIM_MARKER: char = '-';
+const INCLUDE_MARKER: char = '>';
+const ESCAPE: char = '\\';
+const ACTIONS_MARKER: char = '$';
+const RAW: &str = "raw";
+const ENDRAW: &str = "endraw";
+
+/// The number of bytes after the opening delimiter of a tag that koopa would
+/// not interpret where its closing delimiter is looked for. Past this, the
+/// opening delimiter is kept as literal text.
+const LOOKAHEAD: usize = 4 * 1024;
+
+/// The keywords that begin a block tag interpreted by koopa.
+const KEYWORDS: [&str; 8] = ["if", "elif", "else", "endif", "for", "endfor", RAW, ENDRAW];
+
+/// How the lines following the first line of a multi-line value are indented.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Indent {
+    /// Pads each line with spaces up to the column where the value began,
+    /// keeping any tabs before it.
+    #[default]
+    Spaces,
+    /// Repeats the text written before the value on its line, such as tabs,
+    /// comment leaders, or list bullets.
+    Prefix,
+}
+
+/// The character sequences that open and close tags.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Delimiters {
+    variable: (String, String),
+    block: (String, String),
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Delimiters {
+    /// Creates the default delimiters: `{{ }}` for placeholders and `{% %}` for
+    /// blocks.
+    pub fn new() -> Self {
+        Self {
+            variable: (String::from("{{"), String::from("}}")),
+            block: (String::from("{%"), String::from("%}")),
+        }
+    }
+
+    pub fn set_variable(&mut self, open: &str, close: &str) {
+        self.variable = (open.to_string(), close.to_string());
+    }
+
+    pub fn set_block(&mut self, open: &str, close: &str) {
+        self.block = (open.to_string(), close.to_string());
+    }
+
+    /// Checks that the delimiters can be told apart from each other.
+    pub fn validate(&self) -> Result<(), String> {
+        let all = [
+            &self.variable.0,
+            &self.variable.1,
+            &self.block.0,
+            &self.block.1,
+        ];
+        if all.iter().any(|d| d.trim().is_empty()) == true {
+            return Err(String::from("delimiters cannot be empty or whitespace"));
+        }
+        if self.variable.0 == self.block.0 {
+            return Err(format!(
+                "placeholders and blocks cannot both open with \"{}\"",
+                self.variable.0
+            ));
+        }
+        Ok(())
+    }
+
+    /// Accesses the delimiters that open placeholders and blocks.
+    pub(crate) fn openers(&self) -> [&str; 2] {
+        [&self.variable.0, &self.block.0]
+    }
+
+    fn comment(&self) -> (String, String) {
+        (
+            format!("{}{}", self.variable.0, COMMENT_MARKER),
+            format!("{}{}", COMMENT_MARKER, self.variable.1),
+        )
+    }
+}
+
+/// The location of a tag within the source text, where `start` and `end` are
+/// byte offsets and `line` and `col` are where the tag begins. Columns count
+/// characters rather than bytes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+    line: usize,
+    col: usize,
+}
+
+/// The kinds of tags found by the scanner.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TagKind {
+    Var,
+    Block,
+    Comment,
+}
+
+/// A tag found by the scanner, where `inner` is the text between its
+/// delimiters and `source` is the tag's full text.
+#[derive(Debug, PartialEq)]
+struct Tag<'a> {
+    inner: &'a str,
+    source: &'a str,
+    span: Span,
+}
+
+/// A raw piece of the source text as seen by the scanner.
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Text(&'a str),
+    Var(Tag<'a>),
+    Block(Tag<'a>),
+}
+
+/// Splits the text into literal text and the contents of any tags.
+///
+/// A tag that koopa would interpret but is never closed is an error, while any
+/// other opening delimiter that is not closed within [LOOKAHEAD] bytes is kept
+/// as literal text. When the text is not `complete`, more text may follow that
+/// closes the tag, so an unclosed tag is an error unless the text already
+/// reaches past the lookahead. A delimiter preceded by a backslash is kept
+/// as literal text without the backslash, while two backslashes before a
+/// delimiter are written as one. Everything within a `raw` block is kept as
+/// literal text. Lines are counted from `first_line`.
+fn tokenize<'a>(
+    text: &'a str,
+    delims: &Delimiters,
+    first_line: usize,
+    complete: bool,
+) -> Result<Vec<Token<'a>>, Error> {
+    let mut tokens = Vec::new();
+    let mut line = first_line;
+    let mut line_start = 0;
+    let mut cursor = 0;
+
+    // moves the line tracking forward across the given range of the text
+    let mut advance = |from: usize, to: usize, line: &mut usize| {
+        text[from..to].match_indices('\n').for_each(|(i, _)| {
+            *line += 1;
+            line_start = from + i + 1;
+        });
+        line_start
+    };
+
+    let comment = delims.comment();
+    let openers = [
+        (comment.0.as_str(), comment.1.as_str(), TagKind::Comment),
+        (
+            delims.variable.0.as_str(),
+            delims.variable.1.as_str(),
+            TagKind::Var,
+        ),
+        (
+            delims.block.0.as_str(),
+            delims.block.1.as_str(),
+            TagKind::Block,
+        ),
+    ];
+
+    let mut found: Vec<Option<usize>> = openers.iter().map(|o| text.find(o.0)).collect();
+    while let Some((i, opener)) = find_open(text, cursor, &openers, &mut found) {
+        // an opening comment delimiter that is not closed as a comment begins
+        // an ordinary tag instead, such as a Handlebars "{{#each}}" section
+        let comment_end = match opener.2 {
+            TagKind::Comment => find_comment_end(text, i + opener.0.len(), delims),
+            _ => None,
+        };
+        let (open, close, kind) = match (opener.2, comment_end) {
+            (TagKind::Comment, None) => openers[1],
+            _ => opener,
+        };
+        // escaped delimiters are written out as-is, while a doubled backslash
+        // writes one backslash before a tag that is still read
+        if text[cursor..i].ends_with(ESCAPE) == true {
+            let k = i - ESCAPE.len_utf8();
+            tokens.push(Token::Text(&text[cursor..k]));
+            if text[cursor..k].ends_with(ESCAPE) == false {
+                tokens.push(Token::Text(&text[i..i + open.len()]));
+                advance(cursor, i + open.len(), &mut line);
+                cursor = i + open.len();
+                continue;
+            }
+            cursor = i;
+        }
+        let line_begin = advance(cursor, i, &mut line);
+        let col = text[line_begin..i].chars().count() + 1;
+        let start = i + open.len();
+        // a "${{ env.NAME }}" expression belongs to GitHub Actions rather than
+        // to koopa, so it is kept as written
+        let actions = kind == TagKind::Var
+            && text[..i].ends_with(ACTIONS_MARKER) == true
+            && Key::with(first_word(&text[start..]).to_string()).is_env_key() == true;
+        let claimed =
+            kind == TagKind::Comment || (actions == false && is_claimed(&text[start..], kind));
+        let j = match comment_end.or_else(|| text[start..].find(close).map(|j| start + j)) {
+            Some(j) if claimed == true || j - start <= LOOKAHEAD => j,
+            None if claimed == true || (complete == false && text.len() - start <= LOOKAHEAD) => {
+                return Err(Error::TagUnclosed(close.to_string(), line, col))
+            }
+            // the delimiter is kept as literal text, so a stray one does not hold
+            // back the rest of a streamed source
+            _ => {
+                tokens.push(Token::Text(&text[cursor..start]));
+                cursor = start;
+                continue;
+            }
+        };
+        let end = j + close.len();
+        let tag = Tag {
+            inner: &text[start..j],
+            source: &text[i..end],
+            span: Span {
+                start: i,
+                end: end,
+                line: line,
+                col: col,
+            },
+        };
+        // a comment on its own line is removed along with its line
+        let standalone_end = match kind {
+            TagKind::Comment => find_standalone_end(text, line_begin, i, end),
+            _ => None,
+        };
+        match standalone_end {
+            Some(_) => tokens.push(Token::Text(&text[cursor..line_begin.max(cursor)])),
+            None => tokens.push(Token::Text(&text[cursor..i])),
+        }
+        match kind {
+            TagKind::Var if actions == true => tokens.push(Token::Text(tag.source)),
+            TagKind::Var => tokens.push(Token::Var(tag)),
+            TagKind::Comment => {
+                let next = standalone_end.unwrap_or(end);
+                advance(i, next, &mut line);
+                cursor = next;
+                continue;
+            }
+            TagKind::Block => match tag.inner.trim() {
+                RAW => match find_endraw(text, end, delims) {
+                    Some((k, raw_end)) => {
+                        tokens.push(Token::Text(&text[end..k]));
+                        advance(i, raw_end, &mut line);
+                        cursor = raw_end;
+                        continue;
+                    }
+                    None => return Err(Error::BlockUnclosed(String::from(RAW), line, col)),
+                },
+                _ => tokens.push(Token::Block(tag)),
+            },
+        }
+        advance(i, j, &mut line);
+        cursor = end;
+    }
+    if cursor < text.len() {
+        tokens.push(Token::Text(&text[cursor..]));
+    }
+    Ok(tokens
+        .into_iter()
+        .filter(|t| t != &Token::Text(""))
+        .collect())
+}
+
+/// Checks if the text can be split at byte offset `at`, just after a newline,
+/// and have each side rendered on its own without changing the result. This
+/// requires that no whitespace control reaches across the split.
+pub(crate) fn is_boundary(text: &str, at: usize, delims: &Delimiters) -> bool {
+    let rest = &text[at..];
+    let next = rest.trim_start();
+    // the whitespace after the split must end within the text
+    if next.is_empty() == true {
+        return false;
+    }
+    // and must not lead up to a tag that removes it, which cannot be known
+    // until the text reaches past the tag's opening delimiter
+    let trims_before = [&delims.variable.0, &delims.block.0].iter().any(|open| {
+        (next.len() <= open.len() && open.starts_with(next))
+            || next
+                .strip_prefix(open.as_str())
+                .is_some_and(|t| t.starts_with(TRIM_MARKER))
+    });
+    // or follow a tag that removes it
+    let trims_after = next.len() < rest.len()
+        && [&delims.variable.1, &delims.block.1].iter().any(|close| {
+            text[..at]
+                .trim_end()
+                .strip_suffix(close.as_str())
+                .is_some_and(|t| t.ends_with(TRIM_MARKER))
+        });
+    trims_before == false && trims_after == false
+}
+
+/// Checks if a tag whose text after its opening delimiter is `rest` would be
+/// interpreted by koopa rather than kept as literal text. A tag with nothing
+/// but whitespace after its opening delimiter may still be either.
+fn is_claimed(rest: &str, kind: TagKind) -> bool {
+    let word = first_word(rest);
+    let rest = rest.strip_prefix(TRIM_MARKER).unwrap_or(rest).trim_start();
+    match kind {
+        _ if rest.is_empty() == true => true,
+        TagKind::Block => KEYWORDS.contains(&word),
+        _ => {
+            let key = Key::with(word.to_string());
+            rest.starts_with(INCLUDE_MARKER) == true
+                || key.is_koopa_key() == true
+                || key.is_env_key() == true
+        }
+    }
+}
+
+/// Accesses the first word of a tag whose text after its opening delimiter is
+/// `rest`, such as the key of a placeholder or the keyword of a block.
+fn first_word(rest: &str) -> &str {
+    let rest = rest.strip_prefix(TRIM_MARKER).unwrap_or(rest).trim_start();
+    rest.split(|c: char| c.is_whitespace() || c == TRIM_MARKER || c == '|' || c == '?')
+        .next()
+        .unwrap_or("")
+}
+
+/// Checks if the tag spanning `start` to `end` is the only thing on its line
+/// besides whitespace. If so, returns the byte offset just after the line.
+fn find_standalone_end(text: &str, line_begin: usize, start: usize, end: usize) -> Option<usize> {
+    if text[line_begin..start].trim().is_empty() == false {
+        return None;
+    }
+    let rest = &text[end..];
+    match rest.find('\n') {
+        Some(k) if rest[..k].trim().is_empty() => Some(end + k + 1),
+        None if rest.trim().is_empty() => Some(text.len()),
+        _ => None,
+    }
+}
+
+/// Finds where the comment whose text begins at byte offset `from` is closed,
+/// returning the byte offset of its closing delimiter. Placeholders written
+/// within the comment are skipped over, but a closing placeholder delimiter
+/// that belongs to the comment's own tag means it is not a comment at all.
+fn find_comment_end(text: &str, from: usize, delims: &Delimiters) -> Option<usize> {
+    let (open, close) = &delims.variable;
+    let comment_close = delims.comment().1;
+    let mut depth = 0;
+    let mut offset = from;
+    loop {
+        let rest = &text[offset..];
+        let next_open = rest.find(open.as_str());
+        let next_close = rest.find(close.as_str())?;
+        match next_open {
+            Some(k) if k < next_close => {
+                depth += 1;
+                offset += k + open.len();
+            }
+            _ => {
+                let at = offset + next_close;
+                if depth == 0 {
+                    // the comment's closing delimiter ends with the placeholder's
+                    return text[..at + close.len()]
+                        .ends_with(comment_close.as_str())
+                        .then(|| at + close.len() - comment_close.len());
+                }
+                depth -= 1;
+                offset = at + close.len();
+            }
+        }
+    }
+}
+
+/// Finds the block tag that closes a `raw` block, returning the byte offsets
+/// of where the tag begins and ends.
+fn find_endraw(text: &str, from: usize, delims: &Delimiters) -> Option<(usize, usize)> {
+    let (open, close) = &delims.block;
+    let mut offset = from;
+    while let Some(i) = text[offset..].find(open.as_str()) {
+        let start = offset + i + open.len();
+        let j = start + text[start..].find(close.as_str())?;
+        if text[start..j].trim() == ENDRAW {
+            return Some((offset + i, j + close.len()));
+        }
+        offset = start;
+    }
+    None
+}
+
+/// Finds the next opening delimiter at or after `from`, returning its byte
+/// offset along with the matching entry from `openers`. When two delimiters
+/// begin at the same offset, the longer one is chosen.
+///
+/// The offset where each delimiter was last found is kept in `found`, so the
+/// text is only searched again once `from` moves past it.
+fn find_open<'a>(
+    text: &str,
+    from: usize,
+    openers: &[(&'a str, &'a str, TagKind)],
+    found: &mut [Option<usize>],
+) -> Option<(usize, (&'a str, &'a str, TagKind))> {
+    openers
+        .iter()
+        .zip(found.iter_mut())
+        .filter_map(|(o, f)| {
+            if let Some(i) = *f {
+                if i < from {
+                    *f = text[from..].find(o.0).map(|j| from + j);
+                }
+            }
+            f.map(|i| (i, *o))
+        })
+        .min_by(|a, b| a.0.cmp(&b.0).then(b.1 .0.len().cmp(&a.1 .0.len())))
+}
+
+/// A side of a comparison within a conditional expression.
+#[derive(Debug, PartialEq, Clone)]
+enum Operand {
+    Key(Key),
+    Literal(Value),
+}
+
+impl Operand {
+    /// Accesses the value behind the operand, if one exists.
+    fn resolve<'a>(&'a self, ctx: &'a Context) -> Option<&'a Value> {
+        match self {
+            Self::Key(k) => ctx.lookup(k),
+            Self::Literal(v) => Some(v),
+        }
+    }
+
+    fn keys<'a>(&'a self, keys: &mut Vec<&'a Key>) {
+        if let Self::Key(k) = self {
+            keys.push(k);
+        }
+    }
+}
+
+/// A condition guarding a branch of an `if` block.
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Truthy(Operand),
+    Eq(Operand, Operand),
+    Ne(Operand, Operand),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, ctx: &Context) -> bool {
+        match self {
+            Self::Truthy(a) => a.resolve(ctx).is_some_and(is_truthy),
+            Self::Eq(a, b) => Self::compare(a, b, ctx) == Some(true),
+            Self::Ne(a, b) => Self::compare(a, b, ctx) != Some(true),
+            Self::Not(e) => e.eval(ctx) == false,
+            Self::And(a, b) => a.eval(ctx) && b.eval(ctx),
+            Self::Or(a, b) => a.eval(ctx) || b.eval(ctx),
+        }
+    }
+
+    /// Collects every key the condition reads.
+    fn keys<'a>(&'a self, keys: &mut Vec<&'a Key>) {
+        match self {
+            Self::Truthy(a) => a.keys(keys),
+            Self::Eq(a, b) | Self::Ne(a, b) => {
+                a.keys(keys);
+                b.keys(keys);
+            }
+            Self::Not(e) => e.keys(keys),
+            Self::And(a, b) | Self::Or(a, b) => {
+                a.keys(keys);
+                b.keys(keys);
+            }
+        }
+    }
+
+    /// Checks if both operands are equal. Returns `None` if either operand is
+    /// a key that does not exist.
+    fn compare(a: &Operand, b: &Operand, ctx: &Context) -> Option<bool> {
+        Some(a.resolve(ctx)?.to_text() == b.resolve(ctx)?.to_text())
+    }
+}
+
+/// Determines if a value counts as true within a condition.
+///
+/// Empty values, "0", and "false" (in any case) are false, as are empty lists;
+/// everything else is true.
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Str(s) => {
+            let s = s.trim();
+            s.is_empty() == false && s != "0" && s.eq_ignore_ascii_case("false") == false
+        }
+        Value::List(items) => items.is_empty() == false,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ExprToken {
+    Word(String),
+    Str(String),
+    Eq,
+    Ne,
+    LParen,
+    RParen,
+}
+
+fn lex_expr(s: &str) -> Result<Vec<ExprToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            '(' => tokens.push(ExprToken::LParen),
+            ')' => tokens.push(ExprToken::RParen),
+            '=' | '!' => match chars.next() {
+                Some('=') => tokens.push(match c {
+                    '=' => ExprToken::Eq,
+                    _ => ExprToken::Ne,
+                }),
+                _ => return Err(format!("expected '=' after '{}'", c)),
+            },
+            '"' | '\'' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(d) if d == c => break,
+                        Some(d) => word.push(d),
+                        None => return Err(String::from("unterminated string literal")),
+                    }
+                }
+                tokens.push(ExprToken::Str(word));
+            }
+            _ => {
+                let mut word = String::from(c);
+                while let Some(&d) = chars.peek() {
+                    if d.is_whitespace() || "()=!\"'".contains(d) {
+                        break;
+                    }
+                    word.push(d);
+                    chars.next();
+                }
+                tokens.push(ExprToken::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses the text of a condition, where `scope` lists the loop variables
+/// available to reference.
+struct ExprParser<'a> {
+    stream: std::iter::Peekable<std::vec::IntoIter<ExprToken>>,
+    scope: &'a [String],
+}
+
+impl<'a> ExprParser<'a> {
+    fn new(s: &str, scope: &'a [String]) -> Result<Self, String> {
+        Ok(Self {
+            stream: lex_expr(s)?.into_iter().peekable(),
+            scope: scope,
+        })
+    }
+
+    /// Parses the entire text as a single condition.
+    fn parse_expr(s: &str, scope: &'a [String]) -> Result<Expr, String> {
+        let mut parser = Self::new(s, scope)?;
+        let expr = parser.parse_or()?;
+        parser.finish(expr)
+    }
+
+    /// Parses the entire text as a single operand.
+    fn parse_single(s: &str, scope: &'a [String]) -> Result<Operand, String> {
+        let mut parser = Self::new(s, scope)?;
+        let operand = parser.parse_operand()?;
+        parser.finish(operand)
+    }
+
+    fn finish<T>(mut self, result: T) -> Result<T, String> {
+        match self.stream.next() {
+            Some(t) => Err(format!("unexpected token {:?}", t)),
+            None => Ok(result),
+        }
+    }
+
+    fn next_is_word(&mut self, word: &str) -> bool {
+        match self.stream.peek() {
+            Some(ExprToken::Word(w)) if w == word => {
+                self.stream.next();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_and()?;
+        while self.next_is_word("or") == true {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_not()?;
+        while self.next_is_word("and") == true {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, String> {
+        if self.next_is_word("not") == true {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        if self.stream.peek() == Some(&ExprToken::LParen) {
+            self.stream.next();
+            let expr = self.parse_or()?;
+            return match self.stream.next() {
+                Some(ExprToken::RParen) => Ok(expr),
+                _ => Err(String::from("missing closing ')'")),
+            };
+        }
+        let lhs = self.parse_operand()?;
+        match self.stream.peek() {
+            Some(ExprToken::Eq) => {
+                self.stream.next();
+                Ok(Expr::Eq(lhs, self.parse_operand()?))
+            }
+            Some(ExprToken::Ne) => {
+                self.stream.next();
+                Ok(Expr::Ne(lhs, self.parse_operand()?))
+            }
+            _ => Ok(Expr::Truthy(lhs)),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, String> {
+        match self.stream.next() {
+            Some(ExprToken::Str(s)) => Ok(Operand::Literal(Value::from(s))),
+            Some(ExprToken::Word(w)) => {
+                let key = Key::with(w.clone());
+                if key.is_koopa_key() == true || key.is_env_key() == true {
+                    match key.validate() {
+                        Some(e) => Err(Error::lowerize(e.to_string())),
+                        None => Ok(Operand::Key(key)),
+                    }
+                } else if is_in_scope(&w, self.scope) == true {
+                    Ok(Operand::Key(key))
+                } else if w.parse::<f64>().is_ok() {
+                    Ok(Operand::Literal(Value::from(w)))
+                } else {
+                    Err(format!(
+                        "expected a koopa key, loop variable, or literal but found \"{}\"",
+                        w
+                    ))
+                }
+            }
+            Some(t) => Err(format!("expected a koopa key or literal but found {:?}", t)),
+            None => Err(String::from("expected a koopa key or literal")),
+        }
+    }
+}
+
+/// The special variable available within every loop.
+const LOOP_VAR: &str = "loop";
+
+/// Checks if the name refers to a loop variable that is currently bound.
+fn is_in_scope(name: &str, scope: &[String]) -> bool {
+    match name.split_once('.') {
+        Some((LOOP_VAR, _)) => scope.is_empty() == false,
+        Some(_) => false,
+        None => scope.iter().any(|v| v == name),
+    }
+}
+
+/// A statement written within a block tag.
+#[derive(Debug, PartialEq)]
+enum Statement {
+    If(Expr),
+    Elif(Expr),
+    Else,
+    EndIf,
+    For(String, Operand),
+    EndFor,
+}
+
+impl Statement {
+    /// Interprets the contents of a block tag. Returns `None` if the block is
+    /// not a statement known to koopa.
+    fn parse(inner: &str, span: Span, scope: &[String]) -> Result<Option<Self>, Error> {
+        let inner = inner.trim();
+        let (keyword, rest) = inner.split_once(char::is_whitespace).unwrap_or((inner, ""));
+        let invalid =
+            |e: String| Error::ExprInvalid(rest.trim().to_string(), span.line, span.col, e);
+        Ok(match keyword {
+            "if" => Some(Self::If(
+                ExprParser::parse_expr(rest, scope).map_err(invalid)?,
+            )),
+            "elif" => Some(Self::Elif(
+                ExprParser::parse_expr(rest, scope).map_err(invalid)?,
+            )),
+            "else" if rest.trim().is_empty() => Some(Self::Else),
+            "endif" if rest.trim().is_empty() => Some(Self::EndIf),
+            "for" => {
+                let (var, iter) = match rest.trim().split_once(" in ") {
+                    Some((var, iter)) => (var.trim(), iter),
+                    None => return Err(invalid(String::from("expected \"<name> in <key>\""))),
+                };
+                if is_identifier(var) == false || var == LOOP_VAR {
+                    return Err(invalid(format!("invalid loop variable name \"{}\"", var)));
+                }
+                Some(Self::For(
+                    var.to_string(),
+                    ExprParser::parse_single(iter, scope).map_err(invalid)?,
+                ))
+            }
+            "endfor" if rest.trim().is_empty() => Some(Self::EndFor),
+            _ => None,
+        })
+    }
+
+    fn keyword(&self) -> &str {
+        match self {
+            Self::If(_) => "if",
+            Self::Elif(_) => "elif",
+            Self::Else => "else",
+            Self::EndIf => "endif",
+            Self::For(_, _) => "for",
+            Self::EndFor => "endfor",
+        }
+    }
+}
+
+/// Checks if the name only contains letters, digits, and underscores, and
+/// does not begin with a digit.
+fn is_identifier(s: &str) -> bool {
+    s.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_digit() == false)
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A tag to replace with the value of a key after passing it through any
+/// filters.
+#[derive(Debug, PartialEq)]
+struct Placeholder {
+    key: Key,
+    filters: Vec<FilterCall>,
+    /// The original text of the tag.
+    raw: String,
+    span: Span,
+    trim: Trim,
+}
+
+impl Placeholder {
+    fn parse(tag: &Tag, scope: &[String]) -> Result<Self, Error> {
+        let (raw, span) = (tag.source, tag.span);
+        let (trim, inner) = Trim::strip(tag.inner);
+        let mut parts = filter::split_pipes(inner).into_iter();
+        let head = parts.next().unwrap();
+        // a fallback value may follow the key as `key ? "value"`
+        let (key, fallback) = match head.split_once('?') {
+            Some((key, fallback)) => (Key::with(key.to_string()), Some(fallback)),
+            None => (Key::with(head.to_string()), None),
+        };
+        let invalid =
+            |f: &str, e: String| Error::FilterInvalid(f.trim().to_string(), span.line, span.col, e);
+        // only interpret filters for keys that koopa will replace
+        if key.is_koopa_key() == false
+            && key.is_env_key() == false
+            && is_in_scope(key.as_str(), scope) == false
+        {
+            return Ok(Self {
+                key: Key::with(tag.inner.to_string()),
+                filters: Vec::new(),
+                raw: raw.to_string(),
+                span: span,
+                trim: Trim::default(),
+            });
+        }
+        let mut filters = Vec::new();
+        if let Some(fallback) = fallback {
+            filters.push(FilterCall::parse_default(fallback).map_err(|e| invalid(fallback, e))?);
+        }
+        for f in parts {
+            filters.push(FilterCall::parse(f).map_err(|e| invalid(f, e))?);
+        }
+        Ok(Self {
+            key: key,
+            filters: filters,
+            raw: raw.to_string(),
+            span: span,
+            trim: trim,
+        })
+    }
+}
+
+/// Whether the whitespace before and after a tag should be removed, as marked
+/// by a '-' just inside either delimiter.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+struct Trim {
+    before: bool,
+    after: bool,
+}
+
+impl Trim {
+    /// Separates any whitespace-control markers from the contents of a tag.
+    fn strip(inner: &str) -> (Self, &str) {
+        let before = inner.starts_with(TRIM_MARKER);
+        let inner = inner.strip_prefix(TRIM_MARKER).unwrap_or(inner);
+        let after = inner.ends_with(TRIM_MARKER);
+        let inner = inner.strip_suffix(TRIM_MARKER).unwrap_or(inner);
+        (
+            Self {
+                before: before,
+                after: after,
+            },
+            inner,
+        )
+    }
+}
+
+/// A unit of the template to render.
+#[derive(Debug, PartialEq)]
+enum Node {
+    Text(String),
+    Placeholder(Placeholder),
+    Include {
+        name: String,
+        /// The original text of the tag.
+        raw: String,
+        span: Span,
+        trim: Trim,
+    },
+    If {
+        branches: Vec<(Expr, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        var: String,
+        iter: Operand,
+        body: Vec<Node>,
+        span: Span,
+    },
+}
+
+/// The statement that ended a sequence of nodes, along with where it was found.
+type Closing = Option<(Statement, Span)>;
+
+struct Parser<'a> {
+    tokens: std::vec::IntoIter<Token<'a>>,
+    scope: Vec<String>,
+    /// Whether the whitespace at the start of the next text should be removed.
+    trim_next: bool,
+}
+
+impl<'a> Parser<'a> {
+    /// Collects nodes until the tokens run out or a statement is found that
+    /// closes the current block, which is handed back to the caller.
+    fn parse_nodes(&mut self) -> Result<(Vec<Node>, Closing), Error> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.next() {
+            let trim_start = std::mem::take(&mut self.trim_next);
+            match token {
+                Token::Text(s) => {
+                    let s = if trim_start == true {
+                        s.trim_start()
+                    } else {
+                        s
+                    };
+                    nodes.push(Node::Text(s.to_string()))
+                }
+                Token::Var(tag) => {
+                    let (trim, inner) = Trim::strip(tag.inner);
+                    if let Some(name) = inner.trim_start().strip_prefix(INCLUDE_MARKER) {
+                        self.apply_trim(&mut nodes, trim);
+                        nodes.push(Node::Include {
+                            name: name.trim().to_string(),
+                            raw: tag.source.to_string(),
+                            span: tag.span,
+                            trim: trim,
+                        });
+                        continue;
+                    }
+                    let placeholder = Placeholder::parse(&tag, &self.scope)?;
+                    self.apply_trim(&mut nodes, placeholder.trim);
+                    nodes.push(Node::Placeholder(placeholder))
+                }
+                Token::Block(tag) => {
+                    let (trim, inner) = Trim::strip(tag.inner);
+                    let span = tag.span;
+                    let stmt = match Statement::parse(inner, span, &self.scope)? {
+                        Some(stmt) => stmt,
+                        None => {
+                            nodes.push(Node::Text(tag.source.to_string()));
+                            continue;
+                        }
+                    };
+                    self.apply_trim(&mut nodes, trim);
+                    match stmt {
+                        Statement::If(expr) => nodes.push(self.parse_if(expr, span)?),
+                        Statement::For(var, iter) => nodes.push(self.parse_for(var, iter, span)?),
+                        stmt => return Ok((nodes, Some((stmt, span)))),
+                    }
+                }
+            }
+        }
+        Ok((nodes, None))
+    }
+
+    /// Removes the whitespace at the end of the preceding text and marks the
+    /// whitespace at the start of the following text for removal, if requested.
+    fn apply_trim(&mut self, nodes: &mut [Node], trim: Trim) {
+        if trim.before == true {
+            if let Some(Node::Text(s)) = nodes.last_mut() {
+                s.truncate(s.trim_end().len());
+            }
+        }
+        self.trim_next = trim.after;
+    }
+
+    fn parse_if(&mut self, expr: Expr, span: Span) -> Result<Node, Error> {
+        let mut branches = Vec::new();
+        let mut cond = expr;
+        loop {
+            let (body, end) = self.parse_nodes()?;
+            branches.push((cond, body));
+            match end {
+                Some((Statement::Elif(e), _)) => cond = e,
+                Some((Statement::Else, _)) => {
+                    let (otherwise, end) = self.parse_nodes()?;
+                    return match end {
+                        Some((Statement::EndIf, _)) => Ok(Node::If {
+                            branches: branches,
+                            otherwise: otherwise,
+                        }),
+                        end => Err(Self::unmatched("if", span, end)),
+                    };
+                }
+                Some((Statement::EndIf, _)) => {
+                    return Ok(Node::If {
+                        branches: branches,
+                        otherwise: Vec::new(),
+                    })
+                }
+                end => return Err(Self::unmatched("if", span, end)),
+            }
+        }
+    }
+
+    fn parse_for(&mut self, var: String, iter: Operand, span: Span) -> Result<Node, Error> {
+        self.scope.push(var.clone());
+        let (body, end) = self.parse_nodes()?;
+        self.scope.pop();
+        match end {
+            Some((Statement::EndFor, _)) => Ok(Node::For {
+                var: var,
+                iter: iter,
+                body: body,
+                span: span,
+            }),
+            end => Err(Self::unmatched("for", span, end)),
+        }
+    }
+
+    /// Creates the error for when the block at `span` is ended by the wrong
+    /// statement or not ended at all.
+    fn unmatched(block: &str, span: Span, end: Closing) -> Error {
+        match end {
+            Some((stmt, p)) => Error::BlockUnexpected(stmt.keyword().to_string(), p.line, p.col),
+            None => Error::BlockUnclosed(block.to_string(), span.line, span.col),
+        }
+    }
+}
+
+/// The shared state used while rendering a template.
+struct Context<'a> {
+    shells: &'a ShellMap,
+    /// The environment variables available under the "env." namespace.
+    env: ShellMap,
+    delimiters: &'a Delimiters,
+    indent: Indent,
+    filters: &'a Filters,
+    partials: &'a Partials,
+    force: bool,
+    /// Receives messages about the progress of rendering.
+    notify: &'a dyn Fn(Message),
+    /// Values bound by the loops currently being rendered, innermost last.
+    frames: Vec<HashMap<Key, Value>>,
+    /// The text of the template along with its first line number.
+    text: (&'a str, usize),
+    /// Paths and texts of the partials currently being rendered, outermost
+    /// first.
+    includes: Vec<(PathBuf, String)>,
+    /// Problems found so far, in the order they were found.
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Context<'a> {
+    /// Finds the value for the key, checking loop variables before shells and
+    /// environment variables.
+    fn lookup(&self, key: &Key) -> Option<&Value> {
+        self.frames
+            .iter()
+            .rev()
+            .find_map(|frame| frame.get(key))
+            .or_else(|| self.shells.get(key))
+            .or_else(|| self.env.get(key))
+    }
+
+    /// Accesses the source text before the tag at `span` on its line.
+    fn line_before(&self, span: Span) -> &str {
+        let text = match self.includes.last() {
+            Some((_, text)) => text.as_str(),
+            None => self.text.0,
+        };
+        output_line(&text[..span.start])
+    }
+
+    /// Records the error for the tag at `span` and continues rendering, so
+    /// that every problem can be reported at once.
+    fn report(&mut self, error: Error, span: Span, help: Option<String>) {
+        let diagnostic = Diagnostic {
+            error: error,
+            location: Some((span.line, span.col)),
+            len: span.end - span.start,
+            help: help,
+            path: None,
+            excerpt: None,
+        };
+        let diagnostic = match self.includes.last() {
+            Some((path, text)) => diagnostic.within(path.clone(), text),
+            None => diagnostic.quote(self.text.0, self.text.1),
+        };
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn render(&mut self, nodes: &[Node], result: &mut String) {
+        for node in nodes {
+            match node {
+                Node::Text(s) => result.push_str(s),
+                Node::Placeholder(p) => self.render_placeholder(p, result),
+                Node::Include {
+                    name,
+                    raw,
+                    span,
+                    trim,
+                } => {
+                    let path = match self.partials.resolve(name) {
+                        Some(p) => p,
+                        None => {
+                            // the tag may belong to another template language
+                            self.unknown_partial(name, *span);
+                            result.push_str(raw);
+                            continue;
+                        }
+                    };
+                    let before = match trim.before {
+                        true => output_line(result).to_string(),
+                        false => self.line_before(*span).to_string(),
+                    };
+                    if let Some(text) = self.render_include(path, *span) {
+                        // let the partial sit on its own line without adding another
+                        let text = text.strip_suffix('\n').unwrap_or(&text);
+                        push_indented(result, &Value::from(text), &before, self.indent);
+                    }
+                }
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let body = branches
+                        .iter()
+                        .find(|(cond, _)| cond.eval(self) == true)
+                        .map(|(_, body)| body)
+                        .unwrap_or(otherwise);
+                    self.render(body, result);
+                }
+                Node::For {
+                    var,
+                    iter,
+                    body,
+                    span,
+                } => {
+                    let items: Vec<String> = match iter.resolve(self) {
+                        Some(v) => v.items().into_iter().map(String::from).collect(),
+                        None => {
+                            if let Operand::Key(key) = iter {
+                                self.unknown_key(key, *span);
+                            }
+                            Vec::new()
+                        }
+                    };
+                    let length = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let loop_var = |name: &str| Key::with(format!("{}.{}", LOOP_VAR, name));
+                        let mut frame = HashMap::new();
+                        frame.insert(Key::with(var.clone()), Value::from(item));
+                        frame.insert(loop_var("index"), Value::from((i + 1).to_string()));
+                        frame.insert(loop_var("index0"), Value::from(i.to_string()));
+                        frame.insert(loop_var("first"), Value::from((i == 0).to_string()));
+                        frame.insert(loop_var("last"), Value::from((i + 1 == length).to_string()));
+                        frame.insert(loop_var("length"), Value::from(length.to_string()));
+                        self.frames.push(frame);
+                        self.render(body, result);
+                        self.frames.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports a partial that cannot be found, which is only allowed when
+    /// forced.
+    fn unknown_partial(&mut self, name: &str, span: Span) {
+        if self.force == false {
+            let error = Error::PartialUnknown(name.to_string(), span.line, span.col);
+            self.report(error, span, None);
+        } else {
+            (self.notify)(Message::Warning(format!(
+                "skipping unknown partial \"{}\"",
+                name
+            )));
+        }
+    }
+
+    /// Renders the partial at `path` using the current shells and loop
+    /// variables. Returns `None` if the partial could not be rendered.
+    fn render_include(&mut self, path: PathBuf, span: Span) -> Option<String> {
+        if self.includes.iter().any(|(p, _)| p == &path) == true {
+            let mut chain: Vec<String> = self
+                .includes
+                .iter()
+                .map(|(p, _)| format!("{:?}", p))
+                .collect();
+            chain.push(format!("{:?}", path));
+            self.report(Error::PartialCycle(chain.join(" -> ")), span, None);
+            return None;
+        }
+        let text = match std::fs::read_to_string(&path) {
+            Ok(r) => r,
+            Err(e) => {
+                let error = Error::FileRead(path, Error::lowerize(e.to_string()));
+                self.report(error, span, None);
+                return None;
+            }
+        };
+        // partials share the delimiters of the source that includes them
+        let template = match Template::parse_with(&text, self.delimiters) {
+            Ok(t) => t,
+            Err(e) => {
+                self.diagnostics
+                    .push(Diagnostic::from(e).within(path, &text));
+                return None;
+            }
+        };
+        let mut result = String::new();
+        self.includes.push((path, text));
+        self.render(&template.nodes, &mut result);
+        self.includes.pop();
+        Some(result)
+    }
+
+    /// Reports a koopa key that has no value, which is only allowed when
+    /// forced.
+    fn unknown_key(&mut self, key: &Key, span: Span) {
+        if key.is_koopa_key() == true || key.is_env_key() == true {
+            if self.force == false {
+                let help = self.suggest_key(key);
+                self.report(
+                    Error::KeyUnknown(key.clone(), span.line, span.col),
+                    span,
+                    help,
+                );
+            } else {
+                (self.notify)(Message::Warning(format!("skipping unknown key {}", key)));
+            }
+        }
+    }
+
+    /// Finds the known key that is closest in spelling to `key`, if any are
+    /// close enough to be a likely typo.
+    fn suggest_key(&self, key: &Key) -> Option<String> {
+        let known: Vec<&str> = self
+            .shells
+            .inner()
+            .keys()
+            .chain(self.env.inner().keys())
+            .chain(self.frames.iter().flat_map(|f| f.keys()))
+            .map(|k| k.as_str())
+            .collect();
+        suggest(key.as_str(), &known)
+    }
+
+    fn render_placeholder(&mut self, p: &Placeholder, result: &mut String) {
+        let (key, span) = (&p.key, p.span);
+        if key.is_koopa_key() == true || key.is_env_key() == true {
+            // make sure this key being read is valid
+            if let Some(e) = key.validate() {
+                let error = Error::KeyInvalid(
+                    key.clone(),
+                    span.line,
+                    span.col,
+                    Error::lowerize(e.to_string()),
+                );
+                self.report(error, span, None);
+                result.push_str(&p.raw);
+                return;
+            }
+        }
+        // the value begins wherever the output left off when preceding
+        // whitespace is removed
+        let before = match p.trim.before {
+            true => output_line(result).to_string(),
+            false => self.line_before(span).to_string(),
+        };
+        // replace the variable with its value
+        let val = match self.lookup(key) {
+            Some(val) => self.apply_filters(val, &p.filters, span),
+            None => match p.filters.iter().position(|f| f.name() == filter::DEFAULT) {
+                // start from the fallback value and apply the remaining filters
+                Some(i) => match p.filters[i].args() {
+                    [v] => self.apply_filters(v, &p.filters[i + 1..], span),
+                    args => Err((
+                        Error::FilterFailed(
+                            String::from(filter::DEFAULT),
+                            span.line,
+                            span.col,
+                            format!("expected 1 argument but found {}", args.len()),
+                        ),
+                        None,
+                    )),
+                },
+                None => {
+                    // make sure we know this is a missing key if recognized
+                    self.unknown_key(key, span);
+                    result.push_str(&p.raw);
+                    return;
+                }
+            },
+        };
+        match val {
+            Ok(val) => push_indented(result, &val, &before, self.indent),
+            Err((error, help)) => {
+                self.report(error, span, help);
+                result.push_str(&p.raw);
+            }
+        }
+    }
+
+    /// Passes the value through each filter in order. On failure, returns the
+    /// error along with any suggestion to fix it.
+    fn apply_filters(
+        &self,
+        val: &Value,
+        calls: &[FilterCall],
+        span: Span,
+    ) -> Result<Value, (Error, Option<String>)> {
+        let mut val = val.clone();
+        for call in calls {
+            let filter = match self.filters.get(call.name()) {
+                Some(f) => f,
+                None => {
+                    return Err((
+                        Error::FilterUnknown(call.name().to_string(), span.line, span.col),
+                        suggest(call.name(), &self.filters.names()),
+                    ))
+                }
+            };
+            val = match filter(&val, call.args()) {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err((
+                        Error::FilterFailed(call.name().to_string(), span.line, span.col, e),
+                        None,
+                    ))
+                }
+            };
+        }
+        Ok(val)
+    }
+}
+
+/// Creates a "did you mean" message for the candidate most similar to
+/// `word`, if any candidate is within a few edits of it.
+fn suggest(word: &str, candidates: &[&str]) -> Option<String> {
+    let limit = (word.chars().count() / 3).max(1);
+    candidates
+        .iter()
+        .map(|c| (edit_distance(word, c), *c))
+        .filter(|(d, _)| *d > 0 && *d <= limit)
+        .min()
+        .map(|(_, c)| format!("did you mean `{}`?", c))
+}
+
+/// Computes the number of single-character insertions, deletions, and
+/// substitutions needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = match ca == *cb {
+                true => diag,
+                false => 1 + diag.min(above).min(row[j]),
+            };
+            diag = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// A problem found within a template, along with enough information to point
+/// to where it occurred.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    error: Error,
+    /// The line and column where the problem begins, if known.
+    location: Option<(usize, usize)>,
+    /// The number of bytes to underline, starting from the error's column.
+    len: usize,
+    help: Option<String>,
+    /// The path of the file the problem occurred in, if known. A problem
+    /// within a partial keeps the partial's path.
+    path: Option<PathBuf>,
+    /// The line of text the problem occurred on.
+    excerpt: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    pub fn into_error(self) -> Error {
+        self.error
+    }
+
+    /// Returns the line and column where the problem begins, which is only
+    /// known for problems found within a template.
+    pub fn location(&self) -> Option<(usize, usize)> {
+        self.location
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Marks the problem as occurring within the file at `path`, unless it is
+    /// already known to occur within a partial.
+    pub fn in_file(mut self, path: &Path) -> Self {
+        if self.path.is_none() {
+            self.path = Some(path.to_path_buf());
+        }
+        self
+    }
+
+    /// Keeps the line of `text` where the problem occurred so it can be shown
+    /// later, where `text` begins at line `first_line`.
+    pub fn quote(mut self, text: &str, first_line: usize) -> Self {
+        if let Some((line, _)) = self.location {
+            self.excerpt = line
+                .checked_sub(first_line)
+                .and_then(|i| text.split('\n').nth(i))
+                .map(|l| l.trim_end_matches('\r').to_string());
+        }
+        self
+    }
+
+    /// Marks the problem as occurring within the partial at `path`.
+    fn within(mut self, path: PathBuf, text: &str) -> Self {
+        self.path = Some(path);
+        self.quote(text, 1)
+    }
+
+    /// Displays the problem in the style of a compiler error, quoting the line
+    /// where it occurred with the tag underlined. Problems within partials are
+    /// shown with the partial's path instead of `path`.
+    pub fn frame(&self, path: &Path) -> String {
+        let path = self.path.as_deref().unwrap_or(path);
+        let message = self.error.to_string();
+        let mut frame = match self.location {
+            // the location is already given in the frame
+            Some((line, col)) => format!(
+                "error: {}\n",
+                message.replacen(&format!(" at line {} col {}", line, col), "", 1)
+            ),
+            None => format!("error: {}\n", message),
+        };
+        let gutter = " ".repeat(self.location.map_or(0, |(line, _)| line.to_string().len()));
+        match self.location {
+            Some((line, col)) => {
+                frame.push_str(&format!(
+                    "{}--> {}:{}:{}\n",
+                    gutter,
+                    path.display(),
+                    line,
+                    col
+                ));
+                if let Some(source_line) = &self.excerpt {
+                    // the column counts characters
+                    let start = source_line
+                        .char_indices()
+                        .nth(col - 1)
+                        .map_or(source_line.len(), |(i, _)| i);
+                    let end = (start + self.len.max(1))
+                        .min(source_line.len())
+                        .max(start + 1);
+                    // keep tabs so the underline lines up with the quoted line
+                    let padding: String = source_line[..start]
+                        .chars()
+                        .map(|c| if c == '\t' { '\t' } else { ' ' })
+                        .collect();
+                    let underline =
+                        "^".repeat(source_line.get(start..end).map_or(1, |s| s.chars().count()));
+                    frame.push_str(&format!("{} |\n", gutter));
+                    frame.push_str(&format!("{} | {}\n", line, source_line));
+                    frame.push_str(&format!("{} | {}{}\n", gutter, padding, underline));
+                }
+            }
+            None => frame.push_str(&format!(" --> {}\n", path.display())),
+        }
+        if let Some(help) = &self.help {
+            frame.push_str(&format!("{} = help: {}\n", gutter, help));
+        }
+        frame
+    }
+}
+
+impl From<Error> for Diagnostic {
+    fn from(e: Error) -> Self {
+        Self {
+            location: e.location(),
+            error: e,
+            len: 1,
+            help: None,
+            path: None,
+            excerpt: None,
+        }
+    }
+}
+
+/// Accesses the text written to the last line of the result so far.
+fn output_line(result: &str) -> &str {
+    &result[result.rfind('\n').map(|i| i + 1).unwrap_or(0)..]
+}
+
+/// The directories searched for partials, in order of priority.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Partials {
+    dirs: Vec<PathBuf>,
+}
+
+impl Partials {
+    pub fn new() -> Self {
+        Self { dirs: Vec::new() }
+    }
+
+    pub fn with(dirs: Vec<PathBuf>) -> Self {
+        Self { dirs: dirs }
+    }
+
+    /// Finds the first file matching the relative path `name` across the
+    /// directories.
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        self.dirs
+            .iter()
+            .map(|d| d.join(name))
+            .find(|p| p.is_file() == true)
+    }
+}
+
+/// Writes the value to the result, where multi-line values are indented
+/// according to `indent` to line up with where the value began. The text
+/// `before` the value on its line decides the width of the padding.
+fn push_indented(result: &mut String, val: &Value, before: &str, indent: Indent) {
+    let prefix: String = match indent {
+        // each character is padded with a space, except tabs are kept
+        Indent::Spaces => before
+            .chars()
+            .map(|c| match c {
+                '\t' => '\t',
+                _ => ' ',
+            })
+            .collect(),
+        Indent::Prefix => output_line(result).to_string(),
+    };
+    // values may come from files with windows line endings
+    let text = val.to_text().replace("\r\n", "\n");
+    let mut lines = text.split('\n');
+    result.push_str(lines.next().unwrap());
+    for line in lines {
+        result.push('\n');
+        match (indent, line.is_empty()) {
+            // avoid leaving trailing whitespace behind a copied prefix
+            (Indent::Prefix, true) => result.push_str(prefix.trim_end()),
+            _ => result.push_str(&prefix),
+        }
+        result.push_str(line);
+    }
+}
+
+/// A source text parsed into the literal text and koopa tags it contains.
+#[derive(Debug, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+    delimiters: Delimiters,
+    indent: Indent,
+    text: String,
+    first_line: usize,
+}
+
+impl Template {
+    /// Parses the text using the default delimiters.
+    #[cfg(test)]
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        Self::parse_with(text, &Delimiters::new())
+    }
+
+    pub fn parse_with(text: &str, delimiters: &Delimiters) -> Result<Self, Error> {
+        Self::parse_from(text, delimiters, 1, true)
+    }
+
+    /// Parses the text as a piece of a larger source that begins at line
+    /// `first_line`. Unless the piece is `complete`, it may be followed by
+    /// text that closes a tag left open within it.
+    pub(crate) fn parse_from(
+        text: &str,
+        delimiters: &Delimiters,
+        first_line: usize,
+        complete: bool,
+    ) -> Result<Self, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(text, delimiters, first_line, complete)?.into_iter(),
+            scope: Vec::new(),
+            trim_next: false,
+        };
+        match parser.parse_nodes()? {
+            (nodes, None) => Ok(Self {
+                nodes: nodes,
+                delimiters: delimiters.clone(),
+                indent: Indent::default(),
+                text: text.to_string(),
+                first_line: first_line,
+            }),
+            (_, Some((stmt, span))) => Err(Error::BlockUnexpected(
+                stmt.keyword().to_string(),
+                span.line,
+                span.col,
+            )),
+        }
+    }
+
+    /// Sets how multi-line values are indented when rendered.
+    pub fn set_indent(&mut self, indent: Indent) {
+        self.indent = indent;
+    }
+
+    /// Lists the koopa keys that the template reads, in the order they first
+    /// appear. Keys read by partials are not included.
+    pub fn keys(&self) -> Vec<&Key> {
+        let mut keys = Vec::new();
+        Self::collect_keys(&self.nodes, &mut keys);
+        let mut unique: Vec<&Key> = Vec::new();
+        for key in keys {
+            if key.is_koopa_key() == true && unique.contains(&key) == false {
+                unique.push(key);
+            }
+        }
+        unique
+    }
+
+    /// Checks if the template references any koopa or environment keys.
+    pub(crate) fn references_shells(&self) -> bool {
+        let mut keys = Vec::new();
+        Self::collect_keys(&self.nodes, &mut keys);
+        keys.iter()
+            .any(|k| k.is_koopa_key() == true || k.is_env_key() == true)
+    }
+
+    fn collect_keys<'a>(nodes: &'a [Node], keys: &mut Vec<&'a Key>) {
+        for node in nodes {
+            match node {
+                Node::Text(_) | Node::Include { .. } => (),
+                Node::Placeholder(p) => keys.push(&p.key),
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    for (expr, body) in branches {
+                        expr.keys(keys);
+                        Self::collect_keys(body, keys);
+                    }
+                    Self::collect_keys(otherwise, keys);
+                }
+                Node::For { iter, body, .. } => {
+                    iter.keys(keys);
+                    Self::collect_keys(body, keys);
+                }
+            }
+        }
+    }
+
+    /// Produces the final text by replacing every placeholder with its shell
+    /// value and evaluating every block. Fails with the first problem found.
+    pub fn render(
+        &self,
+        shells: &ShellMap,
+        filters: &Filters,
+        partials: &Partials,
+        force: bool,
+        verbose: bool,
+    ) -> Result<String, Error> {
+        self.render_all(shells, filters, partials, force, verbose)
+            .map_err(|mut d| d.remove(0).into_error())
+    }
+
+    /// Produces the final text like [Template::render], but fails with every
+    /// problem found rather than only the first.
+    pub fn render_all(
+        &self,
+        shells: &ShellMap,
+        filters: &Filters,
+        partials: &Partials,
+        force: bool,
+        verbose: bool,
+    ) -> Result<String, Vec<Diagnostic>> {
+        let mut result = String::new();
+        let notify = |m: Message| m.show(verbose);
+        self.render_into(shells, filters, partials, force, &notify, &mut result)?;
+        Ok(result)
+    }
+
+    /// Appends the rendered text to `result`, which may already hold output
+    /// that the text continues from. Messages are given to `notify`.
+    pub(crate) fn render_into(
+        &self,
+        shells: &ShellMap,
+        filters: &Filters,
+        partials: &Partials,
+        force: bool,
+        notify: &dyn Fn(Message),
+        result: &mut String,
+    ) -> Result<(), Vec<Diagnostic>> {
+        let mut context = Context {
+            shells: shells,
+            env: ShellMap::from_env(),
+            delimiters: &self.delimiters,
+            indent: self.indent,
+            filters: filters,
+            partials: partials,
+            force: force,
+            notify: notify,
+            frames: Vec::new(),
+            text: (&self.text, self.first_line),
+            includes: Vec::new(),
+            diagnostics: Vec::new(),
+        };
+        context.render(&self.nodes, result);
+        match context.diagnostics.is_empty() {
+            true => Ok(()),
+            false => Err(context.diagnostics),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::Shell;
+    use std::str::FromStr;
+
+    fn shells(pairs: &[(&str, &str)]) -> ShellMap {
+        let mut shells = ShellMap::new();
+        pairs.iter().for_each(|(k, v)| {
+            shells.insert(Shell::with(k.to_string(), v.to_string()));
+        });
+        shells
+    }
+
+    fn render(text: &str, shells: &ShellMap) -> Result<String, Error> {
+        Template::parse(text)?.render(shells, &Filters::new(), &Partials::new(), false, false)
+    }
+
+    #[test]
+    fn ut_tokenize_spans() {
+        let text = "ab\n  {{ koopa.x }}é{% if koopa.x %}";
+        let tokens = tokenize(text, &Delimiters::new(), 1, true).unwrap();
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(
+            tokens[1],
+            Token::Var(Tag {
+                inner: " koopa.x ",
+                source: "{{ koopa.x }}",
+                span: Span {
+                    start: 5,
+                    end: 18,
+                    line: 2,
+                    col: 3,
+                },
+            })
+        );
+        match &tokens[3] {
+            Token::Block(tag) => {
+                assert_eq!(&text[tag.span.start..tag.span.end], tag.source);
+                assert_eq!((tag.span.line, tag.span.col), (2, 17));
+            }
+            t => panic!("expected a block but found {:?}", t),
+        }
+
+        // tags that are never closed are errors rather than being dropped
+        assert_eq!(
+            render("a\n b {{ koopa.x ", &shells(&[])),
+            Err(Error::TagUnclosed(String::from("}}"), 2, 4))
+        );
+        assert_eq!(
+            render("{% if koopa.x ", &shells(&[])),
+            Err(Error::TagUnclosed(String::from("%}"), 1, 1))
+        );
+        // but ones that koopa would not interpret are kept as literal text
+        let text = "printf(\"%d {%s\", x);\n\\newcommand{%\n}{{ koopa.x }} {{ y";
+        assert_eq!(
+            render(text, &shells(&[("koopa.x", "1")])).unwrap(),
+            "printf(\"%d {%s\", x);\n\\newcommand{%\n}1 {{ y"
+        );
+        assert_eq!(
+            tokenize("{{ y", &Delimiters::new(), 1, false),
+            Err(Error::TagUnclosed(String::from("}}"), 1, 1))
+        );
+        // unless the text already reaches past where it would be closed
+        let text = format!("{{{{ y{}", "\n".repeat(LOOKAHEAD));
+        assert!(tokenize(&text, &Delimiters::new(), 1, false).is_ok());
+    }
+
+    #[test]
+    fn ut_if_truthiness() {
+        let text = "{% if koopa.license %}MIT{% else %}none{% endif %}";
+        assert_eq!(render(text, &shells(&[])).unwrap(), "none");
+        assert_eq!(
+            render(text, &shells(&[("koopa.license", "")])).unwrap(),
+            "none"
+        );
+        assert_eq!(
+            render(text, &shells(&[("koopa.license", "false")])).unwrap(),
+            "none"
+        );
+        assert_eq!(
+            render(text, &shells(&[("koopa.license", "0")])).unwrap(),
+            "none"
+        );
+        assert_eq!(
+            render(text, &shells(&[("koopa.license", "yes")])).unwrap(),
+            "MIT"
+        );
+    }
+
+    #[test]
+    fn ut_if_comparisons() {
+        let text = "{% if koopa.lang == \"vhdl\" %}vhdl{% elif koopa.lang != 'cpp' %}other{% else %}cpp{% endif %}";
+        assert_eq!(
+            render(text, &shells(&[("koopa.lang", "vhdl")])).unwrap(),
+            "vhdl"
+        );
+        assert_eq!(
+            render(text, &shells(&[("koopa.lang", "py")])).unwrap(),
+            "other"
+        );
+        assert_eq!(
+            render(text, &shells(&[("koopa.lang", "cpp")])).unwrap(),
+            "cpp"
+        );
+        assert_eq!(render(text, &shells(&[])).unwrap(), "other");
+
+        let text = "{% if not (koopa.a or koopa.b) and koopa.c == 1 %}yes{% endif %}";
+        assert_eq!(render(text, &shells(&[("koopa.c", "1")])).unwrap(), "yes");
+        assert_eq!(
+            render(text, &shells(&[("koopa.a", "1"), ("koopa.c", "1")])).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn ut_if_nested() {
+        let text = "a{% if koopa.x %}b{% if koopa.y %}{{ koopa.y }}{% endif %}c{% endif %}d";
+        assert_eq!(render(text, &shells(&[("koopa.x", "1")])).unwrap(), "abcd");
+        assert_eq!(
+            render(text, &shells(&[("koopa.x", "1"), ("koopa.y", "Y")])).unwrap(),
+            "abYcd"
+        );
+    }
+
+    #[test]
+    fn ut_if_errors() {
+        assert_eq!(
+            render("\n  {% if koopa.x %}", &shells(&[])),
+            Err(Error::BlockUnclosed(String::from("if"), 2, 3))
+        );
+        assert_eq!(
+            render("{% endif %}", &shells(&[])),
+            Err(Error::BlockUnexpected(String::from("endif"), 1, 1))
+        );
+        assert!(matches!(
+            render("{% if koopa.x == %}{% endif %}", &shells(&[])),
+            Err(Error::ExprInvalid(_, 1, 1, _))
+        ));
+    }
+
+    #[test]
+    fn ut_unknown_blocks_pass_through() {
+        let text = "{% block content %}{{ x }}{% endblock %}";
+        assert_eq!(render(text, &shells(&[])).unwrap(), text);
+    }
+
+    #[test]
+    fn ut_for_list() {
+        let mut map = shells(&[]);
+        map.insert(Shell::from((
+            Key::with(String::from("koopa.ports")),
+            Value::from(vec![String::from("clk"), String::from("rst")]),
+        )));
+        let text = "{% for port in koopa.ports %}{{ loop.index }}:{{ port }}{% if not loop.last %},{% endif %}{% endfor %}";
+        assert_eq!(render(text, &map).unwrap(), "1:clk,2:rst");
+
+        let text =
+            "{% for port in koopa.ports %}{{ port | upper }}{{ loop.first | upper }} {% endfor %}";
+        assert_eq!(render(text, &map).unwrap(), "CLKTRUE RSTFALSE ");
+
+        let text = "{% for port in koopa.ports %}{% for p in koopa.ports %}{% if port != p %}{{ port }}{{ p }} {% endif %}{% endfor %}{% endfor %}";
+        assert_eq!(render(text, &map).unwrap(), "clkrst rstclk ");
+    }
+
+    #[test]
+    fn ut_for_single_and_missing() {
+        let text = "{% for x in koopa.x %}[{{ x }}]{% endfor %}";
+        assert_eq!(render(text, &shells(&[("koopa.x", "a")])).unwrap(), "[a]");
+        assert_eq!(
+            render(text, &shells(&[])),
+            Err(Error::KeyUnknown(Key::with(String::from("koopa.x")), 1, 1))
+        );
+        assert_eq!(
+            Template::parse(text).unwrap().render(
+                &shells(&[]),
+                &Filters::new(),
+                &Partials::new(),
+                true,
+                false
+            ),
+            Ok(String::new())
+        );
+    }
+
+    #[test]
+    fn ut_for_errors() {
+        assert_eq!(
+            render("{% for x in koopa.x %}", &shells(&[])),
+            Err(Error::BlockUnclosed(String::from("for"), 1, 1))
+        );
+        assert_eq!(
+            render("{% for x in koopa.x %}{% endif %}", &shells(&[])),
+            Err(Error::BlockUnexpected(String::from("endif"), 1, 23))
+        );
+        assert!(matches!(
+            render("{% for koopa.x in koopa.x %}{% endfor %}", &shells(&[])),
+            Err(Error::ExprInvalid(_, 1, 1, _))
+        ));
+        // loop variables are only available inside of their loop
+        assert!(matches!(
+            render(
+                "{% for x in koopa.x %}{% endfor %}{% if x %}{% endif %}",
+                &shells(&[])
+            ),
+            Err(Error::ExprInvalid(_, 1, 35, _))
+        ));
+    }
+
+    #[test]
+    fn ut_filters() {
+        let map = shells(&[("koopa.name", "fifo-ctrl")]);
+        assert_eq!(
+            render(
+                "{{ koopa.name | upper_snake }}_H {{koopa.name|pascal}}",
+                &map
+            )
+            .unwrap(),
+            "FIFO_CTRL_H FifoCtrl"
+        );
+        assert_eq!(
+            render("{{ koopa.name | replace(\"-\", \"_\") | upper }}", &map).unwrap(),
+            "FIFO_CTRL"
+        );
+        // filters on keys koopa does not replace are left alone
+        assert_eq!(
+            render("{{ name | upper(( }}", &map).unwrap(),
+            "{{ name | upper(( }}"
+        );
+        assert_eq!(
+            render("{{ koopa.name | nope }}", &map),
+            Err(Error::FilterUnknown(String::from("nope"), 1, 1))
+        );
+        assert!(matches!(
+            render("{{ koopa.name | replace(\"-\") }}", &map),
+            Err(Error::FilterFailed(_, 1, 1, _))
+        ));
+        assert!(matches!(
+            render("{{ koopa.name | replace(- }}", &map),
+            Err(Error::FilterInvalid(_, 1, 1, _))
+        ));
+    }
+
+    #[test]
+    fn ut_custom_filter() {
+        let mut filters = Filters::new();
+        filters.register("shout", |v, _| Ok(Value::from(format!("{}!", v))));
+        let text = "{{ koopa.name | shout }}";
+        assert_eq!(
+            Template::parse(text).unwrap().render(
+                &shells(&[("koopa.name", "hi")]),
+                &filters,
+                &Partials::new(),
+                false,
+                false
+            ),
+            Ok(String::from("hi!"))
+        );
+    }
+
+    #[test]
+    fn ut_default_values() {
+        let map = shells(&[("koopa.empty", "")]);
+        assert_eq!(
+            render("{{ koopa.author ? \"anonymous\" }}", &map).unwrap(),
+            "anonymous"
+        );
+        assert_eq!(
+            render(
+                "{{ koopa.author | upper | default('anon') | pascal }}",
+                &map
+            )
+            .unwrap(),
+            "Anon"
+        );
+        assert_eq!(
+            render("{{ koopa.empty | default(\"x\") }}", &map).unwrap(),
+            "x"
+        );
+        assert_eq!(render("{{ koopa.empty ? \"x\" }}", &map).unwrap(), "x");
+        assert_eq!(
+            render(
+                "{{ koopa.author ? \"a\" }}",
+                &shells(&[("koopa.author", "b")])
+            )
+            .unwrap(),
+            "b"
+        );
+        assert!(matches!(
+            render("{{ koopa.author ? anon }}", &map),
+            Err(Error::FilterInvalid(_, 1, 1, _))
+        ));
+        // other placeholders are still left alone
+        assert_eq!(render("{{ a ? b }}", &map).unwrap(), "{{ a ? b }}");
+    }
+
+    #[test]
+    fn ut_env_keys() {
+        std::env::set_var("KOOPA_UT_ENV", "ci");
+        let map = shells(&[("koopa.name", "fifo")]);
+        assert_eq!(
+            render("{{ koopa.name }} on {{ env.KOOPA_UT_ENV | upper }}", &map).unwrap(),
+            "fifo on CI"
+        );
+        assert_eq!(
+            render("{% if env.KOOPA_UT_ENV == \"ci\" %}yes{% endif %}", &map).unwrap(),
+            "yes"
+        );
+        assert_eq!(
+            render("{{ env.KOOPA_UT_MISSING ? \"none\" }}", &map).unwrap(),
+            "none"
+        );
+        // github actions expressions are kept as written
+        assert_eq!(
+            render(
+                "run: echo ${{ env.KOOPA_UT_ENV }} ${{ env.KOOPA_UT_MISSING }} ${{ koopa.name }}",
+                &map
+            )
+            .unwrap(),
+            "run: echo ${{ env.KOOPA_UT_ENV }} ${{ env.KOOPA_UT_MISSING }} $fifo"
+        );
+        assert_eq!(
+            render("{{ env.KOOPA_UT_MISSING }}", &map),
+            Err(Error::KeyUnknown(
+                Key::from_str("env.KOOPA_UT_MISSING").unwrap(),
+                1,
+                1
+            ))
+        );
+        assert_eq!(
+            Template::parse("{{ env.KOOPA_UT_MISSING }}")
+                .unwrap()
+                .render(&map, &Filters::new(), &Partials::new(), true, false),
+            Ok(String::from("{{ env.KOOPA_UT_MISSING }}"))
+        );
+    }
+
+    #[test]
+    fn ut_escape_and_raw() {
+        let map = shells(&[("koopa.name", "fifo")]);
+        assert_eq!(
+            render("\\{{ koopa.name }} is {{ koopa.name }}, \\{% if %}", &map).unwrap(),
+            "{{ koopa.name }} is fifo, {% if %}"
+        );
+        let text = "a{% raw %}{{ koopa.missing }} {% if koopa.x %}{{ koopa.a.b }}{% endraw %}{{ koopa.name }}";
+        assert_eq!(
+            render(text, &map).unwrap(),
+            "a{{ koopa.missing }} {% if koopa.x %}{{ koopa.a.b }}fifo"
+        );
+        assert_eq!(
+            render("\n {%raw%}{{ koopa.x }}", &map),
+            Err(Error::BlockUnclosed(String::from("raw"), 2, 2))
+        );
+        // an escaped tag inside a raw block is left untouched
+        assert_eq!(
+            render("{% raw %}\\{{ x }}{% endraw %}", &map).unwrap(),
+            "\\{{ x }}"
+        );
+        // a doubled backslash is a literal backslash before a tag
+        let map = shells(&[("koopa.x", "bin"), ("koopa.name", "fifo")]);
+        assert_eq!(
+            render("C:\\\\{{ koopa.x }}\\{{ koopa.name }}", &map).unwrap(),
+            "C:\\bin{{ koopa.name }}"
+        );
+    }
+
+    #[test]
+    fn ut_comments() {
+        let map = shells(&[("koopa.name", "fifo")]);
+        assert_eq!(
+            render("a {{# note #}}b {{ koopa.name }}", &map).unwrap(),
+            "a b fifo"
+        );
+        assert_eq!(
+            render(
+                "line 1\n  {{# update when the toolchain changes #}}  \nline 2\n{{# last #}}",
+                &map
+            )
+            .unwrap(),
+            "line 1\nline 2\n"
+        );
+        assert_eq!(
+            render(
+                "{{# a {{ koopa.missing }}\nmulti-line note #}}\n{{ koopa.name }}",
+                &map
+            )
+            .unwrap(),
+            "fifo"
+        );
+        // errors after a comment still report the correct position
+        assert_eq!(
+            render("{{# one\ntwo #}}\n  {{ koopa.x }}", &map),
+            Err(Error::KeyUnknown(
+                Key::with(String::from(" koopa.x ")),
+                3,
+                3
+            ))
+        );
+    }
+
+    #[test]
+    fn ut_handlebars_sections_pass_through() {
+        let map = shells(&[("koopa.name", "fifo")]);
+        let text = "{{#each items}}<li>{{this}}</li>{{/each}} {{ koopa.name }}";
+        assert_eq!(
+            render(text, &map).unwrap(),
+            "{{#each items}}<li>{{this}}</li>{{/each}} fifo"
+        );
+        assert_eq!(
+            Template::parse(text).unwrap().render(
+                &map,
+                &Filters::new(),
+                &Partials::new(),
+                true,
+                false
+            ),
+            Ok(String::from(
+                "{{#each items}}<li>{{this}}</li>{{/each}} fifo"
+            ))
+        );
+        // a comment still closes after the sections it contains
+        assert_eq!(
+            render("{{# {{#if x}}{{/if}} #}}{{ koopa.name }}", &map).unwrap(),
+            "fifo"
+        );
+    }
+
+    #[test]
+    fn ut_whitespace_control() {
+        let map = shells(&[("koopa.x", ""), ("koopa.y", "Y")]);
+        assert_eq!(render("a  {{- koopa.x -}}  \n b", &map).unwrap(), "ab");
+        assert_eq!(render("a  {{- koopa.y }}  b", &map).unwrap(), "aY  b");
+        assert_eq!(
+            render("<\n  {%- if koopa.y -%}\n  yes\n  {%- endif -%}\n>", &map).unwrap(),
+            "<yes>"
+        );
+        assert_eq!(
+            render(
+                "<\n{% if koopa.x -%}\n  yes\n{%- else -%}\n  no\n{%- endif %}\n>",
+                &map
+            )
+            .unwrap(),
+            "<\nno\n>"
+        );
+        // markers on tags koopa does not replace are left alone
+        assert_eq!(render("a {{- x -}} b", &map).unwrap(), "a {{- x -}} b");
+
+        // multi-line values are indented to where the value begins
+        let map = shells(&[("koopa.m", "1\n2")]);
+        assert_eq!(render("ab\n    {{- koopa.m }}", &map).unwrap(), "ab1\n  2");
+    }
+
+    #[test]
+    fn ut_indent_prefix() {
+        let map = shells(&[("koopa.license", "MIT License\n\nCopyright (c) koopa")]);
+        let render = |text: &str, indent: Indent| {
+            let mut template = Template::parse(text).unwrap();
+            template.set_indent(indent);
+            template
+                .render(&map, &Filters::new(), &Partials::new(), false, false)
+                .unwrap()
+        };
+        assert_eq!(
+            render("// {{ koopa.license }}\nint main();", Indent::Prefix),
+            "// MIT License\n//\n// Copyright (c) koopa\nint main();"
+        );
+        assert_eq!(
+            render("\t-- {{ koopa.license }}", Indent::Prefix),
+            "\t-- MIT License\n\t--\n\t-- Copyright (c) koopa"
+        );
+        // prefixes are copied as written, regardless of their width in bytes
+        assert_eq!(
+            render("  * é {{ koopa.license }}", Indent::Prefix),
+            "  * é MIT License\n  * é\n  * é Copyright (c) koopa"
+        );
+        // padding keeps tabs and counts characters rather than bytes
+        assert_eq!(
+            render("\t// {{ koopa.license }}", Indent::Spaces),
+            "\t// MIT License\n\t   \n\t   Copyright (c) koopa"
+        );
+        assert_eq!(
+            render("é {{ koopa.license }}", Indent::Spaces),
+            "é MIT License\n  \n  Copyright (c) koopa"
+        );
+    }
+
+    #[test]
+    fn ut_diagnostics() {
+        let map = shells(&[("koopa.project", "fifo"), ("koopa.author", "chase")]);
+        let text = "name: {{ koopa.projct }}\n\tby {{ koopa.author | uper }} {{ koopa.zzz }}\n";
+        let diagnostics = Template::parse(text)
+            .unwrap()
+            .render_all(&map, &Filters::new(), &Partials::new(), false, false)
+            .unwrap_err();
+        // every problem is found rather than only the first
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|d| d.error())
+                .collect::<Vec<&Error>>(),
+            vec![
+                &Error::KeyUnknown(Key::from_str("koopa.projct").unwrap(), 1, 7),
+                &Error::FilterUnknown(String::from("uper"), 2, 5),
+                &Error::KeyUnknown(Key::from_str("koopa.zzz").unwrap(), 2, 31),
+            ]
+        );
+        let path = Path::new("src/main.cpp");
+        assert_eq!(
+            diagnostics[0].frame(path),
+            "\
+error: unknown key \"{{ koopa.projct }}\"
+ --> src/main.cpp:1:7
+  |
+1 | name: {{ koopa.projct }}
+  |       ^^^^^^^^^^^^^^^^^^
+  = help: did you mean `koopa.project`?
+"
+        );
+        assert_eq!(
+            diagnostics[1].frame(path),
+            "\
+error: unknown filter \"uper\"
+ --> src/main.cpp:2:5
+  |
+2 | \tby {{ koopa.author | uper }} {{ koopa.zzz }}
+  | \t   ^^^^^^^^^^^^^^^^^^^^^^^^^
+  = help: did you mean `upper`?
+"
+        );
+        assert_eq!(diagnostics[2].help, None);
+
+        // columns count characters rather than bytes
+        let diagnostics = Template::parse("é {{ koopa.zz }}")
+            .unwrap()
+            .render_all(&map, &Filters::new(), &Partials::new(), false, false)
+            .unwrap_err();
+        assert_eq!(
+            diagnostics[0].frame(path),
+            "\
+error: unknown key \"{{ koopa.zz }}\"
+ --> src/main.cpp:1:3
+  |
+1 | é {{ koopa.zz }}
+  |   ^^^^^^^^^^^^^^
+"
+        );
+
+        // syntax errors point to where the problem was found
+        let e = Template::parse("a\n  {% if koopa.x %}").unwrap_err();
+        assert_eq!(
+            Diagnostic::from(e)
+                .quote("a\n  {% if koopa.x %}", 1)
+                .frame(path),
+            "\
+error: missing closing tag for \"if\" block
+ --> src/main.cpp:2:3
+  |
+2 |   {% if koopa.x %}
+  |   ^
+"
+        );
+    }
+
+    #[test]
+    fn ut_partials() {
+        let dir = std::env::temp_dir().join("koopa-ut-partials");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("headers")).unwrap();
+        std::fs::write(dir.join("headers/banner"), "-- {{ koopa.name }}\n-- MIT\n").unwrap();
+        std::fs::write(dir.join("a"), "{{> b }}").unwrap();
+        std::fs::write(dir.join("b"), "{{> a }}").unwrap();
+        let partials = Partials::with(vec![PathBuf::from("missing"), dir.clone()]);
+        let map = shells(&[("koopa.name", "fifo")]);
+        let render = |text: &str| {
+            Template::parse(text)
+                .unwrap()
+                .render(&map, &Filters::new(), &partials, false, false)
+        };
+
+        assert_eq!(
+            render("  {{> headers/banner }}\nend").unwrap(),
+            "  -- fifo\n  -- MIT\nend"
+        );
+        assert_eq!(
+            render("x\n{{> nope }}"),
+            Err(Error::PartialUnknown(String::from("nope"), 2, 1))
+        );
+        assert!(matches!(render("{{> a }}"), Err(Error::PartialCycle(_))));
+        // unknown partials may belong to another template language
+        assert_eq!(
+            Template::parse("{{> nope }}\n{{> headers/banner }}")
+                .unwrap()
+                .render(&map, &Filters::new(), &partials, true, false),
+            Ok(String::from("{{> nope }}\n-- fifo\n-- MIT"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ut_custom_delimiters() {
+        let mut delims = Delimiters::new();
+        delims.set_variable("<%", "%>");
+        delims.set_block("<%!", "!%>");
+        let map = shells(&[("koopa.name", "fifo")]);
+        let text = "{{ .Values.x }} ${{ matrix.os }} <% koopa.name | upper %><%# note #%>\
+            <%! if koopa.name !%>!<%! endif !%> \\<% x %>";
+        assert_eq!(
+            Template::parse_with(text, &delims)
+                .unwrap()
+                .render(&map, &Filters::new(), &Partials::new(), false, false)
+                .unwrap(),
+            "{{ .Values.x }} ${{ matrix.os }} FIFO! <% x %>"
+        );
+
+        let mut delims = Delimiters::new();
+        delims.set_variable("@@", "@@");
+        assert_eq!(
+            Template::parse_with("a @@ koopa.name @@ {{ koopa.name }}", &delims)
+                .unwrap()
+                .render(&map, &Filters::new(), &Partials::new(), false, false)
+                .unwrap(),
+            "a fifo {{ koopa.name }}"
+        );
+        delims.set_block("@@", "%}");
+        assert!(delims.validate().is_err());
+    }
+}